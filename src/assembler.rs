@@ -0,0 +1,636 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::encoding;
+use crate::error::{Error, LoxErrors, LoxResult};
+use crate::function::{ByteCodeFunction, UpvalueDescriptor};
+use crate::generator::ByteCode;
+use crate::line_table::LineTable;
+use crate::value::Value;
+
+/// Builds a `ByteCodeFunction` from text in the format printed by
+/// `disassemble`, so that `disassemble(&assemble(listing)?)` gives back
+/// `listing`.
+///
+/// Programs can also be written by hand:
+///
+/// ```text
+///     LdaConstant 5
+/// L1:
+///     Print
+///     JumpIfTrue L1
+/// ```
+///
+/// The offset and source line columns of a listing are optional; an
+/// instruction without them is attributed to its line in the text. So is
+/// the `== script (registers N) ==` header, in which case the frame is
+/// sized to fit the registers the script uses.
+///
+/// Every `Closure` refers to the next function section that has not been
+/// claimed yet, which is the order the disassembler lists them in.
+pub fn assemble(source: &str) -> LoxResult<ByteCodeFunction> {
+    let mut sections: Vec<Section> = vec![];
+    for (index, text) in source.lines().enumerate() {
+        let number = index + 1;
+        let text = text.trim();
+        if text.is_empty() {
+            continue;
+        }
+        if let Some(header) = text.strip_prefix("==") {
+            sections.push(Section::parse_header(header, number)?);
+            continue;
+        }
+        if sections.is_empty() {
+            sections.push(Section::new("script".to_string()));
+        }
+        let section = sections.last_mut().unwrap();
+
+        if let Some(label) = text.strip_suffix(':') {
+            let offset = section.instructions.len();
+            if section.labels.insert(label.to_string(), offset).is_some() {
+                return Err(error(number, format!("Label '{label}' is already bound.")));
+            }
+            continue;
+        }
+        let previous_line = section.instructions.last().map(|i| i.line);
+        let instruction = Instruction::parse(text, number, previous_line)?;
+        section.instructions.push(instruction);
+    }
+
+    let mut sections = sections.into_iter();
+    let script = match sections.next() {
+        Some(script) => script.build(&mut sections)?,
+        None => Section::new("script".to_string()).build(&mut sections)?,
+    };
+    match sections.next() {
+        Some(section) => Err(error(
+            section.number,
+            format!("No Closure creates function '{}'.", section.name),
+        )),
+        None => Ok(script),
+    }
+}
+
+/// The instructions listed under one `== name (...) ==` header.
+struct Section<'a> {
+    name: String,
+    /// Line of the header in the text.
+    number: usize,
+    arity: usize,
+    register_count: Option<usize>,
+    upvalues: Vec<UpvalueDescriptor>,
+    labels: HashMap<String, usize>,
+    instructions: Vec<Instruction<'a>>,
+}
+
+impl<'a> Section<'a> {
+    fn new(name: String) -> Self {
+        Self {
+            name,
+            number: 1,
+            arity: 0,
+            register_count: None,
+            upvalues: vec![],
+            labels: HashMap::new(),
+            instructions: vec![],
+        }
+    }
+
+    /// Parses `name (arity 2, registers 4, captures R1 U0) ==`.
+    fn parse_header(header: &str, number: usize) -> LoxResult<Self> {
+        let header = header.trim().strip_suffix("==").unwrap_or(header).trim();
+        let (name, details) = match header.split_once('(') {
+            Some((name, details)) => {
+                let details = details.strip_suffix(')').ok_or_else(|| {
+                    error(number, "Expected ')' after function details.".to_string())
+                })?;
+                (name.trim(), details)
+            }
+            None => (header, ""),
+        };
+        if name.is_empty() {
+            return Err(error(number, "Expected a function name.".to_string()));
+        }
+
+        let mut section = Self::new(name.to_string());
+        section.number = number;
+        for detail in details.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            let mut words = detail.split_whitespace();
+            match words.next() {
+                Some("arity") => section.arity = count(words.next(), number)?,
+                Some("registers") => section.register_count = Some(count(words.next(), number)?),
+                Some("captures") => {
+                    for word in words.by_ref() {
+                        let is_local = word.starts_with('R');
+                        let index = if is_local {
+                            register(word, number)?
+                        } else {
+                            upvalue(word, number)?
+                        };
+                        section.upvalues.push(UpvalueDescriptor { is_local, index });
+                    }
+                }
+                _ => {
+                    return Err(error(
+                        number,
+                        format!("Unknown function detail '{detail}'."),
+                    ))
+                }
+            }
+            if words.next().is_some() {
+                return Err(error(
+                    number,
+                    format!("Unknown function detail '{detail}'."),
+                ));
+            }
+        }
+        Ok(section)
+    }
+
+    /// Generates the function, taking the functions its closures create
+    /// from `rest`.
+    fn build(self, rest: &mut impl Iterator<Item = Section<'a>>) -> LoxResult<ByteCodeFunction> {
+        let mut bytecodes = vec![];
+        let mut lines = LineTable::default();
+        let mut highest_register = 0;
+        for (index, instruction) in self.instructions.iter().enumerate() {
+            let mut operands = Operands {
+                words: instruction.operands.iter(),
+                number: instruction.number,
+                labels: &self.labels,
+                register_count: self.register_count,
+                highest_register: &mut highest_register,
+                upvalue_count: self.upvalues.len(),
+            };
+            let bytecode = operands.bytecode(instruction.mnemonic, rest)?;
+            operands.finish()?;
+            bytecodes.push(bytecode);
+            lines.push(index, instruction.line);
+        }
+
+        let function = ByteCodeFunction::new(
+            self.name,
+            self.arity,
+            self.register_count.unwrap_or(highest_register as usize + 1),
+            &bytecodes,
+            &lines,
+            self.upvalues,
+        );
+        // The same checks a compiled file goes through when it is loaded.
+        if encoding::decode(&function).is_none() {
+            return Err(error(
+                self.number,
+                format!("Function '{}' is not valid bytecode.", function.name),
+            ));
+        }
+        Ok(function)
+    }
+}
+
+/// One instruction line, split into its mnemonic and operand words.
+struct Instruction<'a> {
+    /// Line of the instruction in the text.
+    number: usize,
+    /// Source line recorded for the instruction.
+    line: usize,
+    mnemonic: &'a str,
+    operands: Vec<&'a str>,
+}
+
+impl<'a> Instruction<'a> {
+    fn parse(text: &'a str, number: usize, previous_line: Option<usize>) -> LoxResult<Self> {
+        let mut words = split_words(text, number)?;
+        let mut line = number;
+        if words[0].starts_with(|c: char| c.is_ascii_digit()) {
+            // `0004    2 Add R1 R2 R3`: the offset is implied by the position
+            // of the instruction and `|` repeats the previous line.
+            words.remove(0);
+            match words.first() {
+                Some(&"|") => line = previous_line.unwrap_or(number),
+                Some(word) => line = count(Some(word), number)?,
+                None => {}
+            }
+            if !words.is_empty() {
+                words.remove(0);
+            }
+        }
+        if words.is_empty() {
+            return Err(error(number, "Expected an instruction.".to_string()));
+        }
+        let mnemonic = words.remove(0);
+        Ok(Self {
+            number,
+            line,
+            mnemonic,
+            operands: words,
+        })
+    }
+}
+
+/// The operands of an instruction, read in order.
+struct Operands<'a, 'b> {
+    words: std::slice::Iter<'a, &'b str>,
+    number: usize,
+    labels: &'a HashMap<String, usize>,
+    /// Size of the frame given in the header, which registers must fit in.
+    register_count: Option<usize>,
+    highest_register: &'a mut i32,
+    /// Number of variables the function captures.
+    upvalue_count: usize,
+}
+
+impl<'b> Operands<'_, 'b> {
+    fn bytecode(
+        &mut self,
+        mnemonic: &str,
+        rest: &mut impl Iterator<Item = Section<'b>>,
+    ) -> LoxResult<ByteCode> {
+        let bytecode = match mnemonic {
+            "Return" => ByteCode::Return,
+            "Call" => {
+                let callee = self.register()?;
+                let mut arguments = vec![];
+                while self.words.len() > 0 {
+                    arguments.push(self.register()?);
+                }
+                ByteCode::Call(callee, arguments)
+            }
+            "Closure" => {
+                let name = self.name()?;
+                let function = match rest.next() {
+                    Some(section) if section.name == name => section.build(rest)?,
+                    _ => return Err(self.error(format!("Expected the body of function '{name}'."))),
+                };
+                let register_count = self.register_count.unwrap_or(encoding::MAX_REGISTERS);
+                encoding::check_closure(&function, register_count, self.upvalue_count)
+                    .map_err(|message| self.error(message))?;
+                // A frame sized from its instructions must also hold the
+                // registers the closure captures.
+                for upvalue in function.upvalues.iter().filter(|upvalue| upvalue.is_local) {
+                    *self.highest_register = (*self.highest_register).max(upvalue.index);
+                }
+                ByteCode::Closure(Rc::new(function))
+            }
+            "LdaUpvalue" => ByteCode::LdaUpvalue(self.upvalue()?),
+            "StaUpvalue" => ByteCode::StaUpvalue(self.upvalue()?),
+            "CloseUpvalue" => ByteCode::CloseUpvalue(self.register()?),
+            "Class" => {
+                let name = self.name()?;
+                let superclass = match self.words.len() {
+                    0 => None,
+                    _ => Some(self.register()?),
+                };
+                ByteCode::Class(name, superclass)
+            }
+            "Method" => ByteCode::Method(self.register()?, self.name()?),
+            "GetProperty" => ByteCode::GetProperty(self.name()?),
+            "SetProperty" => ByteCode::SetProperty(self.register()?, self.name()?),
+            "GetSuper" => ByteCode::GetSuper(self.name()?),
+            "Ldar" => ByteCode::Ldar(self.register()?),
+            "Star" => ByteCode::Star(self.register()?),
+            "Move" => ByteCode::Move(self.register()?, self.register()?),
+            "LdaUndefined" => ByteCode::LdaUndefined,
+            "LdaNil" => ByteCode::LdaNil,
+            "LdaConstant" => ByteCode::LdaConstant(self.constant()?),
+            "Add" => ByteCode::Add(self.register()?),
+            "Mul" => ByteCode::Mul(self.register()?),
+            "Sub" => ByteCode::Sub(self.register()?),
+            "Div" => ByteCode::Div(self.register()?),
+            "Equal" => ByteCode::Equal(self.register()?),
+            "NotEqual" => ByteCode::NotEqual(self.register()?),
+            "Negate" => ByteCode::Negate,
+            "LogicalNot" => ByteCode::LogicalNot,
+            "LdaGlobal" => ByteCode::LdaGlobal(self.name()?),
+            "StaGlobal" => ByteCode::StaGlobal(self.name()?),
+            "DefineGlobal" => ByteCode::DefineGlobal(self.name()?),
+            "Jump" => ByteCode::Jump(self.label()?),
+            "JumpIfFalse" => ByteCode::JumpIfFalse(self.label()?),
+            "JumpIfTrue" => ByteCode::JumpIfTrue(self.label()?),
+            "TestLessThan" => ByteCode::TestLessThan(self.register()?),
+            "TestGreaterThan" => ByteCode::TestGreaterThan(self.register()?),
+            "TestLessEqThan" => ByteCode::TestLessEqThan(self.register()?),
+            "TestGreaterEqThan" => ByteCode::TestGreaterEqThan(self.register()?),
+            "Print" => ByteCode::Print,
+            _ => return Err(self.error(format!("Unknown instruction '{mnemonic}'."))),
+        };
+        Ok(bytecode)
+    }
+
+    fn next(&mut self, expected: &str) -> LoxResult<&'b str> {
+        match self.words.next() {
+            Some(word) => Ok(*word),
+            None => Err(self.error(format!("Expected {expected}."))),
+        }
+    }
+
+    fn register(&mut self) -> LoxResult<i32> {
+        let number = self.number;
+        let register = register(self.next("a register")?, number)?;
+        self.use_register(register)
+    }
+
+    /// Checks that `register` fits in the frame, or grows the frame to fit
+    /// it when the header doesn't give its size.
+    fn use_register(&mut self, register: i32) -> LoxResult<i32> {
+        if let Some(count) = self
+            .register_count
+            .filter(|&count| register as usize >= count)
+        {
+            return Err(self.error(format!(
+                "R{register} is outside of a frame of {count} registers."
+            )));
+        }
+        *self.highest_register = (*self.highest_register).max(register);
+        Ok(register)
+    }
+
+    fn upvalue(&mut self) -> LoxResult<i32> {
+        let number = self.number;
+        let upvalue = upvalue(self.next("an upvalue")?, number)?;
+        self.check_upvalue(upvalue)
+    }
+
+    fn check_upvalue(&self, upvalue: i32) -> LoxResult<i32> {
+        if upvalue as usize >= self.upvalue_count {
+            return Err(self.error(format!(
+                "U{upvalue} is outside of the {} upvalues the function captures.",
+                self.upvalue_count
+            )));
+        }
+        Ok(upvalue)
+    }
+
+    fn name(&mut self) -> LoxResult<String> {
+        Ok(self.next("a name")?.to_string())
+    }
+
+    fn label(&mut self) -> LoxResult<usize> {
+        let label = self.next("a label")?;
+        match self.labels.get(label) {
+            Some(offset) => Ok(*offset),
+            None => Err(self.error(format!("Undefined label '{label}'."))),
+        }
+    }
+
+    fn constant(&mut self) -> LoxResult<Value> {
+        let word = self.next("a constant")?;
+        if let Some(quoted) = word.strip_prefix('"') {
+            return match quoted.strip_suffix('"').and_then(unescape) {
+                Some(string) => Ok(Value::String(string)),
+                None => Err(self.error(format!("Expected a string but found '{word}'."))),
+            };
+        }
+        match word {
+            "true" => Ok(Value::Boolean(true)),
+            "false" => Ok(Value::Boolean(false)),
+            "nil" => Ok(Value::Nil),
+            _ => match word.parse() {
+                Ok(number) => Ok(Value::Number(number)),
+                Err(_) => Err(self.error(format!("Expected a constant but found '{word}'."))),
+            },
+        }
+    }
+
+    fn finish(mut self) -> LoxResult<()> {
+        match self.words.next() {
+            Some(word) => Err(self.error(format!("Unexpected operand '{word}'."))),
+            None => Ok(()),
+        }
+    }
+
+    fn error(&self, message: String) -> LoxErrors {
+        error(self.number, message)
+    }
+}
+
+/// Splits an instruction line on whitespace, keeping quoted strings whole.
+fn split_words(text: &str, number: usize) -> LoxResult<Vec<&str>> {
+    let mut words = vec![];
+    let mut rest = text.trim_start();
+    while !rest.is_empty() {
+        let end = if let Some(quoted) = rest.strip_prefix('"') {
+            let mut escaped = false;
+            let close = quoted.find(|c| {
+                let closes = c == '"' && !escaped;
+                escaped = c == '\\' && !escaped;
+                closes
+            });
+            match close {
+                Some(close) => close + 2,
+                None => return Err(error(number, "Unterminated string.".to_string())),
+            }
+        } else {
+            rest.find(char::is_whitespace).unwrap_or(rest.len())
+        };
+        words.push(&rest[..end]);
+        rest = rest[end..].trim_start();
+    }
+    Ok(words)
+}
+
+/// Reverses the escaping `{:?}` applies to the strings of `LdaConstant`.
+fn unescape(quoted: &str) -> Option<String> {
+    let mut string = String::new();
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            string.push(c);
+            continue;
+        }
+        let escaped = match chars.next()? {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            '0' => '\0',
+            '\\' => '\\',
+            '"' => '"',
+            '\'' => '\'',
+            'u' => {
+                let code = chars.as_str().strip_prefix('{')?;
+                let (hex, rest) = code.split_once('}')?;
+                let c = char::from_u32(u32::from_str_radix(hex, 16).ok()?)?;
+                chars = rest.chars();
+                c
+            }
+            _ => return None,
+        };
+        string.push(escaped);
+    }
+    Some(string)
+}
+
+fn register(word: &str, number: usize) -> LoxResult<i32> {
+    match word.strip_prefix('R').map(str::parse::<u16>) {
+        Some(Ok(register)) => Ok(register as i32),
+        _ => Err(error(
+            number,
+            format!("Expected a register but found '{word}'."),
+        )),
+    }
+}
+
+fn upvalue(word: &str, number: usize) -> LoxResult<i32> {
+    match word.strip_prefix('U').map(str::parse::<u16>) {
+        Some(Ok(index)) => Ok(index as i32),
+        _ => Err(error(
+            number,
+            format!("Expected an upvalue but found '{word}'."),
+        )),
+    }
+}
+
+fn count(word: Option<&str>, number: usize) -> LoxResult<usize> {
+    match word.map(str::parse) {
+        Some(Ok(count)) => Ok(count),
+        _ => Err(error(number, "Expected a number.".to_string())),
+    }
+}
+
+fn error(number: usize, message: String) -> LoxErrors {
+    LoxErrors::ParseError(Error::new(number, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disassembler::disassemble;
+    use crate::generator::ByteCodeGenerator;
+    use crate::parser::Parser;
+    use crate::resolver::Resolver;
+    use crate::scanner::Scanner;
+    use crate::vm::Vm;
+
+    #[test]
+    fn test_disassembly_round_trips() {
+        let source = "class A { init(x) { this.x = x; } get() { return this.x; } }
+             class B < A { init() { super.init(\"b\\q\n\"); } }
+             fun counter() {
+               var count = 0;
+               fun increment() { count = count + 1; return count; }
+               return increment;
+             }
+             fun script(a) { return a; }
+             var one = script(1);
+             var i = 0;
+             while (i < 3 and !false) { i = i + 1; }
+             print -i * 2.5 / 4 - 1 != nil;";
+        let tokens = Scanner::new(source.to_string()).scan_tokens();
+        let statements = Parser::new(&tokens).parse().unwrap();
+        Resolver::default().resolve(&statements).unwrap();
+        let mut generator = ByteCodeGenerator::default();
+        generator.intrepret(&statements).unwrap();
+
+        let listing = disassemble(&generator.script());
+        let script = assemble(&listing).unwrap();
+        assert_eq!(disassemble(&script), listing);
+
+        let mut vm = Vm::new(script);
+        vm.interpret().unwrap();
+        assert_eq!(vm.variables.get("one"), Some(&Value::Number(1.0)));
+    }
+
+    #[test]
+    fn test_hand_written_program_runs() {
+        let script = assemble(
+            "    LdaConstant 0
+                 Star R1
+                 LdaConstant 3
+                 Star R2
+             L1:
+                 LdaConstant 1
+                 Add R1
+                 Star R1
+                 Ldar R2
+                 TestLessThan R1
+                 JumpIfTrue L1
+                 Ldar R1
+                 DefineGlobal total
+                 LdaConstant \"done\\n\"
+                 DefineGlobal message",
+        )
+        .unwrap();
+        assert_eq!(script.register_count, 3);
+
+        let mut vm = Vm::new(script);
+        vm.interpret().unwrap();
+        assert_eq!(vm.variables.get("total"), Some(&Value::Number(3.0)));
+        assert_eq!(
+            vm.variables.get("message"),
+            Some(&Value::String("done\n".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_errors_point_at_the_offending_line() {
+        let error = assemble("LdaConstant 1\nJump L9").err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Error occured at line 2: Undefined label 'L9'."
+        );
+        let error = assemble("Move R1 5").err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Error occured at line 1: Expected a register but found '5'."
+        );
+        let error = assemble("== script (registers 2) ==\nStar R2")
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Error occured at line 2: R2 is outside of a frame of 2 registers."
+        );
+    }
+
+    #[test]
+    fn test_captures_and_upvalues_must_exist() {
+        let error = assemble("LdaUpvalue U3").err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Error occured at line 1: U3 is outside of the 0 upvalues the function captures."
+        );
+        let error = assemble(
+            "== script (registers 2) ==
+             Closure f
+             == f (arity 0, registers 1, captures R9) ==
+             Return",
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Error occured at line 2: R9 is outside of a frame of 2 registers."
+        );
+        let error = assemble(
+            "Closure f
+             == f (arity 0, registers 1, captures U0) ==
+             Return",
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Error occured at line 1: U0 is outside of the 0 upvalues the function captures."
+        );
+        let error = assemble(
+            "Closure f
+             == f (arity 2, registers 2) ==
+             Return",
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Error occured at line 1: Function 'f' has no room for its 2 parameters."
+        );
+    }
+
+    #[test]
+    fn test_methods_on_non_classes_fail_at_runtime() {
+        let script = assemble("LdaConstant 1\nStar R1\nMethod R1 foo").unwrap();
+        let error = Vm::new(script).interpret().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Error occured at line 3: Only classes have methods."
+        );
+    }
+}
@@ -1,11 +1,23 @@
+use std::cell::Cell;
+
 use crate::tools::{AstNode, AstStmt};
 use crate::{define_ast, token::Token, value::Value as LiteralEnum};
 
 // expression     → assignment ;
-// assignment     → IDENTIFIER "=" assignment
+// assignment     → ( call "." )? IDENTIFIER "=" assignment
 //                | logic_or ;
 // logic_or       → logic_and ( "or" logic_and )* ;
 // logic_and      → equality ( "and" equality )* ;
+// unary          → ( "!" | "-" ) unary | call ;
+// call           → primary ( "(" arguments? ")" | "." IDENTIFIER )* ;
+// arguments      → expression ( "," expression )* ;
+// primary        → "true" | "false" | "nil" | "this"
+//                | NUMBER | STRING | IDENTIFIER | "(" expression ")"
+//                | "super" "." IDENTIFIER ;
+//
+// Nodes that name a variable carry a `depth` filled in by the `Resolver`:
+// the number of scopes between the use and the declaration it refers to,
+// or `None` for a global.
 
 define_ast!(
     AstNode,
@@ -22,7 +34,8 @@ define_ast!(
     [
         Assign {
             name: Token,
-            value: Box<Expr>
+            value: Box<Expr>,
+            depth: Cell<Option<usize>>
         },
         visit_assign_expr
     ],
@@ -34,7 +47,8 @@ define_ast!(
     ],
     [
         Literal {
-            value: Option<LiteralEnum>
+            value: Option<LiteralEnum>,
+            line: usize
         },
         visit_literal_expr
     ],
@@ -47,7 +61,8 @@ define_ast!(
     ],
     [
         Variable {
-            name: Token
+            name: Token,
+            depth: Cell<Option<usize>>
         },
         visit_variable_expr
     ],
@@ -59,15 +74,67 @@ define_ast!(
         },
         visit_logical_expr
     ],
+    [
+        Call {
+            callee: Box<Expr>,
+            paren: Token,
+            arguments: Vec<Expr>
+        },
+        visit_call_expr
+    ],
+    [
+        Get {
+            object: Box<Expr>,
+            name: Token
+        },
+        visit_get_expr
+    ],
+    [
+        Set {
+            object: Box<Expr>,
+            name: Token,
+            value: Box<Expr>
+        },
+        visit_set_expr
+    ],
+    [
+        This {
+            keyword: Token,
+            depth: Cell<Option<usize>>
+        },
+        visit_this_expr
+    ],
+    [
+        Super {
+            keyword: Token,
+            method: Token,
+            depth: Cell<Option<usize>>
+        },
+        visit_super_expr
+    ],
 );
 
+// declaration    → classDecl
+//                | funDecl
+//                | varDecl
+//                | statement ;
+//
+// classDecl      → "class" IDENTIFIER ( "<" IDENTIFIER )?
+//                  "{" function* "}" ;
+// funDecl        → "fun" function ;
+// function       → IDENTIFIER "(" parameters? ")" block ;
+// parameters     → IDENTIFIER ( "," IDENTIFIER )* ;
+//
 // statement      → exprStmt
 //                | ifStmt
 //                | printStmt
+//                | returnStmt
 //                | forStmt
 //                | whileStmt
 //                | block ;
 //
+// returnStmt     → "return" expression? ";" ;
+//
 // whileStmt      → "while" "(" expression ")" statement ;
 //
 // ifStmt         → "if" "(" expression ")" statement
@@ -87,4 +154,7 @@ define_ast!(
     [VarStmt { name: Token , initializer: Option<Expr> }, visit_var_stmt],
     [IfStmt { condition: Expr , then_branch: Box<Stmt>, else_branch: Option<Box<Stmt>>}, visit_if_stmt],
     [WhileStmt { condition: Expr, body: Box<Stmt>}, visit_while_stmt],
+    [FunctionStmt { name: Token, params: Vec<Token>, body: Vec<Stmt> }, visit_function_stmt],
+    [ReturnStmt { keyword: Token, value: Option<Expr> }, visit_return_stmt],
+    [ClassStmt { name: Token, superclass: Option<Variable>, methods: Vec<FunctionStmt> }, visit_class_stmt],
 );
@@ -0,0 +1,418 @@
+use std::rc::Rc;
+
+use crate::constant_pool::ConstantPool;
+use crate::encoding;
+use crate::error::{LoxErrors, LoxResult};
+use crate::function::{ByteCodeFunction, UpvalueDescriptor};
+use crate::line_table::LineTable;
+use crate::value::Value;
+
+/// First bytes of every compiled bytecode file.
+pub const MAGIC: &[u8; 4] = b"LOXC";
+/// Bumped whenever the layout below or the meaning of an instruction
+/// changes, so that stale files are rejected instead of misread.
+pub const VERSION: u16 = 6;
+
+/*
+    file           → MAGIC version:u16 constants code lines functions ;
+    constants      → count:u32 constant* ;
+    constant       → 0 | 1 bool:u8 | 2 number:f64 | 3 length:u32 utf8 ;
+    code           → length:u32 byte* ;
+    lines          → count:u32 ( offset:u32 line:u32 )* ;
+    functions      → count:u32 function* ;
+    function       → name:u32 arity:u32 registers:u32
+                     upvalues:u32 ( is_local:u8 index:u32 )*
+                     constants:u32 constant_index:u32*
+                     functions:u32 function_index:u32*
+                     code_start:u32 code_length:u32
+                     lines_start:u32 lines_length:u32 ;
+
+    Integers are little endian. The code of every function is stored as it
+    is encoded in memory, described in `encoding`. A function lists its
+    constants as indices into the constant pool and the functions its
+    closures create as indices into the function table. The script is the
+    first function and every function comes before the ones it creates.
+*/
+
+const NIL: u8 = 0;
+const BOOLEAN: u8 = 1;
+const NUMBER: u8 = 2;
+const STRING: u8 = 3;
+
+/// Serializes `script` and every function nested in it.
+pub fn encode(script: &ByteCodeFunction) -> Vec<u8> {
+    let mut functions = vec![];
+    collect_functions(script, &mut functions);
+
+    let mut constants = ConstantPool::default();
+    let mut code: Vec<u8> = vec![];
+    let mut lines = vec![];
+    let mut table = vec![];
+    for function in functions.iter() {
+        let name = constants.add(Value::String(function.name.clone()));
+        put_u32(&mut table, name);
+        put_u32(&mut table, function.arity);
+        put_u32(&mut table, function.register_count);
+        put_u32(&mut table, function.upvalues.len());
+        for upvalue in function.upvalues.iter() {
+            table.push(upvalue.is_local as u8);
+            put_u32(&mut table, upvalue.index as usize);
+        }
+        put_u32(&mut table, function.constants.len());
+        for constant in function.constants.iter() {
+            put_u32(&mut table, constants.add(constant.clone()));
+        }
+        put_u32(&mut table, function.functions.len());
+        for nested in function.functions.iter() {
+            let index = functions
+                .iter()
+                .position(|candidate| std::ptr::eq(*candidate, nested.as_ref()))
+                .unwrap();
+            put_u32(&mut table, index);
+        }
+        put_u32(&mut table, code.len());
+        put_u32(&mut table, function.code.len());
+        code.extend(&function.code);
+        put_u32(&mut table, lines.len() / 8);
+        put_u32(&mut table, function.lines.runs().len());
+        for &(offset, line) in function.lines.runs() {
+            put_u32(&mut lines, offset);
+            put_u32(&mut lines, line);
+        }
+    }
+
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend(VERSION.to_le_bytes());
+    put_u32(&mut bytes, constants.len());
+    for constant in constants.iter() {
+        match constant {
+            Value::Nil => bytes.push(NIL),
+            Value::Boolean(boolean) => bytes.extend([BOOLEAN, *boolean as u8]),
+            Value::Number(number) => {
+                bytes.push(NUMBER);
+                bytes.extend(number.to_le_bytes());
+            }
+            Value::String(string) => {
+                bytes.push(STRING);
+                put_u32(&mut bytes, string.len());
+                bytes.extend(string.as_bytes());
+            }
+            _ => unreachable!("only literals are constants"),
+        }
+    }
+    put_u32(&mut bytes, code.len());
+    bytes.extend(code);
+    put_u32(&mut bytes, lines.len() / 8);
+    bytes.extend(lines);
+    put_u32(&mut bytes, functions.len());
+    bytes.extend(table);
+    bytes
+}
+
+/// Lists `function` and the functions it creates, parents first.
+fn collect_functions<'a>(
+    function: &'a ByteCodeFunction,
+    functions: &mut Vec<&'a ByteCodeFunction>,
+) {
+    functions.push(function);
+    for nested in function.functions.iter() {
+        collect_functions(nested, functions);
+    }
+}
+
+fn put_u32(bytes: &mut Vec<u8>, value: usize) {
+    bytes.extend((value as u32).to_le_bytes());
+}
+
+/// Loads a script written by `encode`.
+pub fn decode(bytes: &[u8]) -> LoxResult<ByteCodeFunction> {
+    let mut reader = Reader { bytes, position: 0 };
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(invalid("not a compiled Lox script"));
+    }
+    let version = u16::from_le_bytes(reader.take(2)?.try_into().unwrap());
+    if version != VERSION {
+        return Err(invalid(&format!(
+            "compiled for bytecode version {version}, but this build runs version {VERSION}. Compile the script again"
+        )));
+    }
+
+    let mut constants = vec![];
+    for _ in 0..reader.u32()? {
+        let constant = match reader.u8()? {
+            NIL => Value::Nil,
+            BOOLEAN => Value::Boolean(reader.u8()? != 0),
+            NUMBER => Value::Number(f64::from_le_bytes(reader.take(8)?.try_into().unwrap())),
+            STRING => {
+                let length = reader.u32()?;
+                match String::from_utf8(reader.take(length)?.to_vec()) {
+                    Ok(string) => Value::String(string),
+                    Err(_) => return Err(corrupt()),
+                }
+            }
+            _ => return Err(corrupt()),
+        };
+        constants.push(constant);
+    }
+    let code_length = reader.u32()?;
+    let code = reader.take(code_length)?;
+    let mut lines = vec![];
+    for _ in 0..reader.u32()? {
+        lines.push((reader.u32()?, reader.u32()?));
+    }
+
+    let mut headers = vec![];
+    for _ in 0..reader.u32()? {
+        let name = reader.u32()?;
+        let arity = reader.u32()?;
+        let register_count = reader.u32()?;
+        let mut upvalues = vec![];
+        for _ in 0..reader.u32()? {
+            upvalues.push(UpvalueDescriptor {
+                is_local: reader.u8()? != 0,
+                index: reader.u32()? as i32,
+            });
+        }
+        let mut function_constants = vec![];
+        for _ in 0..reader.u32()? {
+            function_constants.push(reader.u32()?);
+        }
+        let mut nested = vec![];
+        for _ in 0..reader.u32()? {
+            nested.push(reader.u32()?);
+        }
+        headers.push(FunctionHeader {
+            name,
+            arity,
+            register_count,
+            upvalues,
+            constants: function_constants,
+            functions: nested,
+            code: (reader.u32()?, reader.u32()?),
+            lines: (reader.u32()?, reader.u32()?),
+        });
+    }
+    if reader.position != bytes.len() || headers.is_empty() {
+        return Err(corrupt());
+    }
+
+    // Functions only create the ones listed after them, so building from
+    // the end of the table makes every nested function available first.
+    let mut functions: Vec<Option<Rc<ByteCodeFunction>>> = vec![None; headers.len()];
+    let mut script = None;
+    for (index, header) in headers.into_iter().enumerate().rev() {
+        let mut pool = ConstantPool::default();
+        for (position, &constant) in header.constants.iter().enumerate() {
+            let constant = constants.get(constant).ok_or_else(corrupt)?;
+            if pool.add(constant.clone()) != position {
+                return Err(corrupt());
+            }
+        }
+        let mut nested = vec![];
+        for &function in header.functions.iter() {
+            match functions.get(function) {
+                Some(Some(function)) => nested.push(function.clone()),
+                _ => return Err(corrupt()),
+            }
+        }
+        for function in nested.iter() {
+            encoding::check_closure(function, header.register_count, header.upvalues.len())
+                .map_err(|_| corrupt())?;
+        }
+        let name = match constants.get(header.name) {
+            Some(Value::String(name)) => name.clone(),
+            _ => return Err(corrupt()),
+        };
+        let code = slice(code, header.code)?.to_vec();
+
+        let runs = slice(&lines, header.lines)?;
+        let mut table = LineTable::default();
+        for (run, &(start, line)) in runs.iter().enumerate() {
+            let end = runs.get(run + 1).map_or(code.len(), |&(next, _)| next);
+            if start >= end || (run == 0 && start != 0) {
+                return Err(corrupt());
+            }
+            table.push(start, line);
+        }
+        if runs.is_empty() != code.is_empty() {
+            return Err(corrupt());
+        }
+
+        let function = ByteCodeFunction {
+            name,
+            arity: header.arity,
+            register_count: header.register_count,
+            code,
+            lines: table,
+            constants: pool,
+            functions: nested,
+            upvalues: header.upvalues,
+        };
+        encoding::decode(&function).ok_or_else(corrupt)?;
+        if index == 0 {
+            script = Some(function);
+        } else {
+            functions[index] = Some(Rc::new(function));
+        }
+    }
+    Ok(script.unwrap())
+}
+
+struct FunctionHeader {
+    name: usize,
+    arity: usize,
+    register_count: usize,
+    upvalues: Vec<UpvalueDescriptor>,
+    /// Constant pool index of each of the function's constants.
+    constants: Vec<usize>,
+    /// Function table index of each function the function's closures create.
+    functions: Vec<usize>,
+    /// Start and length of the function's code in bytes.
+    code: (usize, usize),
+    /// Start and length of the function's runs in the line table.
+    lines: (usize, usize),
+}
+
+fn slice<T>(items: &[T], (start, length): (usize, usize)) -> LoxResult<&[T]> {
+    items.get(start..start + length).ok_or_else(corrupt)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> LoxResult<&'a [u8]> {
+        let bytes = self
+            .bytes
+            .get(self.position..self.position + length)
+            .ok_or_else(corrupt)?;
+        self.position += length;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> LoxResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> LoxResult<usize> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()) as usize)
+    }
+}
+
+fn invalid(message: &str) -> LoxErrors {
+    LoxErrors::InvalidBytecode(message.to_string())
+}
+
+fn corrupt() -> LoxErrors {
+    invalid("the file is truncated or corrupt")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disassembler::disassemble;
+    use crate::generator::{ByteCode, ByteCodeGenerator};
+    use crate::parser::Parser;
+    use crate::resolver::Resolver;
+    use crate::scanner::Scanner;
+    use crate::vm::Vm;
+
+    fn compile(source: &str) -> ByteCodeFunction {
+        let tokens = Scanner::new(source.to_string()).scan_tokens();
+        let statements = Parser::new(&tokens).parse().unwrap();
+        Resolver::default().resolve(&statements).unwrap();
+        let mut generator = ByteCodeGenerator::default();
+        generator.intrepret(&statements).unwrap();
+        generator.script()
+    }
+
+    #[test]
+    fn test_decoded_script_matches_and_runs() {
+        let script = compile(
+            "class Point { init(x) { this.x = x; } }
+             class Named < Point { init() { super.init(\"origin\"); } }
+             fun counter() {
+               var count = 0;
+               fun increment() { count = count + 1; return count; }
+               return increment;
+             }
+             var next = counter();
+             next();
+             var total = next() * 2.5;
+             var name = Named().x;
+             var done = !(name == nil);",
+        );
+        let decoded = decode(&encode(&script)).unwrap();
+        assert_eq!(disassemble(&decoded), disassemble(&script));
+
+        let mut vm = Vm::new(decoded);
+        vm.interpret().unwrap();
+        assert_eq!(vm.variables.get("total"), Some(&Value::Number(5.0)));
+        assert_eq!(
+            vm.variables.get("name"),
+            Some(&Value::String("origin".to_string()))
+        );
+        assert_eq!(vm.variables.get("done"), Some(&Value::Boolean(true)));
+    }
+
+    #[test]
+    fn test_other_versions_and_truncated_files_are_rejected() {
+        let mut bytes = encode(&compile("print 1;"));
+        bytes[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert_eq!(
+            decode(&bytes).err().unwrap().to_string(),
+            format!(
+                "Invalid bytecode file: compiled for bytecode version {}, but this build runs version {VERSION}. Compile the script again.",
+                VERSION + 1
+            )
+        );
+
+        let bytes = encode(&compile("print 1;"));
+        assert!(decode(&bytes[..bytes.len() - 1]).is_err());
+        assert!(decode(b"print 1;").is_err());
+    }
+
+    #[test]
+    fn test_functions_that_overflow_their_frame_are_rejected() {
+        let script = |arity, upvalue| {
+            let mut lines = LineTable::default();
+            lines.push(0, 1);
+            let nested = ByteCodeFunction::new(
+                "f".to_string(),
+                arity,
+                2,
+                &[ByteCode::Return],
+                &lines,
+                vec![upvalue],
+            );
+            let closure = ByteCode::Closure(Rc::new(nested));
+            ByteCodeFunction::new("script".to_string(), 0, 2, &[closure], &lines, vec![])
+        };
+        let local = |index| UpvalueDescriptor {
+            is_local: true,
+            index,
+        };
+        assert!(decode(&encode(&script(1, local(1)))).is_ok());
+        assert!(decode(&encode(&script(2, local(1)))).is_err());
+        assert!(decode(&encode(&script(1, local(2)))).is_err());
+        let enclosing = UpvalueDescriptor {
+            is_local: false,
+            index: 0,
+        };
+        assert!(decode(&encode(&script(1, enclosing))).is_err());
+
+        let mut lines = LineTable::default();
+        lines.push(0, 1);
+        let huge = ByteCodeFunction::new(
+            "script".to_string(),
+            0,
+            encoding::MAX_REGISTERS + 1,
+            &[ByteCode::Return],
+            &lines,
+            vec![],
+        );
+        assert!(decode(&encode(&huge)).is_err());
+    }
+}
@@ -0,0 +1,121 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::bytecode_file;
+use crate::error::LoxResult;
+use crate::function::ByteCodeFunction;
+
+/// Directory created next to a script to hold its compiled bytecode.
+pub const CACHE_DIRECTORY: &str = "__loxcache__";
+
+/// Returns the bytecode of the script at `path`, whose contents are
+/// `source`, from its cache when the cache was built from the same source
+/// by the same compiler. Otherwise `compile` is called and its result
+/// cached for the next run.
+///
+/// A cache that is stale, corrupt or can't be written is never an error:
+/// the script is compiled as if there was no cache.
+pub fn load_or_compile(
+    path: &Path,
+    source: &str,
+    compile: impl FnOnce() -> LoxResult<ByteCodeFunction>,
+) -> LoxResult<ByteCodeFunction> {
+    let cache_path = cache_path(path);
+    let key = key(source);
+    if let Some(script) = fs::read(&cache_path)
+        .ok()
+        .and_then(|bytes| Some(bytes.strip_prefix(key.as_slice())?.to_vec()))
+        .and_then(|bytes| bytecode_file::decode(&bytes).ok())
+    {
+        return Ok(script);
+    }
+
+    let script = compile()?;
+    let mut bytes = key;
+    bytes.extend(bytecode_file::encode(&script));
+    if let Some(directory) = cache_path.parent() {
+        let _ = fs::create_dir_all(directory).and_then(|_| fs::write(&cache_path, bytes));
+    }
+    Ok(script)
+}
+
+/// `examples/loop.lox` is cached in `examples/__loxcache__/loop.loxc`.
+fn cache_path(path: &Path) -> PathBuf {
+    let directory = path.parent().unwrap_or(Path::new("")).join(CACHE_DIRECTORY);
+    let file_name = path.file_name().unwrap_or_default();
+    directory.join(Path::new(file_name).with_extension("loxc"))
+}
+
+/// What a cache file starts with: the compiler version followed by a hash
+/// of the source it was built from.
+fn key(source: &str) -> Vec<u8> {
+    let mut key = compiler_version().into_bytes();
+    key.push(b'\n');
+    key.extend(fnv1a(source.as_bytes()).to_le_bytes());
+    key
+}
+
+/// The package version alone would keep serving bytecode from before a
+/// change to the generator, so the time the running executable was built
+/// is part of the version too.
+fn compiler_version() -> String {
+    let built = std::env::current_exe()
+        .and_then(fs::metadata)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |built| built.as_nanos());
+    format!("{}+{built}", env!("CARGO_PKG_VERSION"))
+}
+
+/// The 64-bit FNV-1a hash, which unlike `DefaultHasher` is the same for
+/// every build.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disassembler::disassemble;
+    use crate::generator::ByteCodeGenerator;
+    use crate::parser::Parser;
+    use crate::resolver::Resolver;
+    use crate::scanner::Scanner;
+
+    fn compile(source: &str) -> LoxResult<ByteCodeFunction> {
+        let tokens = Scanner::new(source.to_string()).scan_tokens();
+        let statements = Parser::new(&tokens).parse()?;
+        Resolver::default().resolve(&statements)?;
+        let mut generator = ByteCodeGenerator::default();
+        generator.intrepret(&statements)?;
+        Ok(generator.script())
+    }
+
+    #[test]
+    fn test_cache_is_reused_until_the_source_changes() {
+        let directory = std::env::temp_dir().join(format!("lox-cache-{}", std::process::id()));
+        let path = directory.join("script.lox");
+        let cache = directory.join(CACHE_DIRECTORY).join("script.loxc");
+        let source = "var a = 1;";
+
+        let script = load_or_compile(&path, source, || compile(source)).unwrap();
+        assert!(cache.exists());
+        let cached = load_or_compile(&path, source, || panic!("the cache was not used")).unwrap();
+        assert_eq!(disassemble(&cached), disassemble(&script));
+
+        let edited = "var a = 2;";
+        let script = load_or_compile(&path, edited, || compile(edited)).unwrap();
+        assert!(disassemble(&script).contains("LdaConstant 2"));
+
+        fs::write(&cache, b"garbage").unwrap();
+        load_or_compile(&path, edited, || compile(edited)).unwrap();
+        let cached =
+            load_or_compile(&path, edited, || panic!("the cache was not rebuilt")).unwrap();
+        assert_eq!(disassemble(&cached), disassemble(&script));
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
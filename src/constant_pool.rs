@@ -0,0 +1,75 @@
+use std::collections::HashMap;
+
+use crate::value::Value;
+
+/// The literals a function loads, each stored once and referred to by
+/// index from `LdaConstant`.
+#[derive(Debug, Default)]
+pub struct ConstantPool {
+    constants: Vec<Value>,
+    /// Index of each number by its bits, so that `0` and `-0` stay apart.
+    numbers: HashMap<u64, usize>,
+    strings: HashMap<String, usize>,
+}
+
+impl ConstantPool {
+    /// Returns the index of `value`, adding it unless it is already pooled.
+    pub fn add(&mut self, value: Value) -> usize {
+        let existing = match &value {
+            Value::Number(number) => self.numbers.get(&number.to_bits()).copied(),
+            Value::String(string) => self.strings.get(string).copied(),
+            value => self.constants.iter().position(|constant| constant == value),
+        };
+        if let Some(index) = existing {
+            return index;
+        }
+
+        let index = self.constants.len();
+        match &value {
+            Value::Number(number) => {
+                self.numbers.insert(number.to_bits(), index);
+            }
+            Value::String(string) => {
+                self.strings.insert(string.clone(), index);
+            }
+            _ => {}
+        }
+        self.constants.push(value);
+        index
+    }
+
+    pub fn get(&self, index: usize) -> &Value {
+        &self.constants[index]
+    }
+
+    pub fn len(&self) -> usize {
+        self.constants.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Value> {
+        self.constants.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_equal_literals_share_an_index() {
+        let mut pool = ConstantPool::default();
+        let one = pool.add(Value::Number(1.0));
+        let hello = pool.add(Value::String("hello".to_string()));
+        let zero = pool.add(Value::Number(0.0));
+
+        assert_eq!(pool.add(Value::Number(1.0)), one);
+        assert_eq!(pool.add(Value::String("hello".to_string())), hello);
+        assert_ne!(pool.add(Value::Number(-0.0)), zero);
+        assert_eq!(
+            pool.add(Value::Boolean(true)),
+            pool.add(Value::Boolean(true))
+        );
+        assert_eq!(pool.len(), 5);
+        assert_eq!(pool.get(hello), &Value::String("hello".to_string()));
+    }
+}
@@ -0,0 +1,160 @@
+use std::fmt::Write;
+
+use crate::encoding;
+use crate::function::ByteCodeFunction;
+use crate::generator::ByteCode;
+
+/// Renders `function` and every function nested in it as a listing with
+/// one instruction per line:
+///
+/// ```text
+/// == script (arity 0, registers 2) ==
+/// 0000    1 LdaConstant 0
+/// 0002    | DefineGlobal i
+/// L0:
+/// 0004    2 LdaGlobal i
+/// ```
+///
+/// Each line holds the offset of the instruction in the encoded code, the
+/// source line (`|` when it is the same as the previous instruction's) and
+/// the instruction. Jump targets are shown as labels, which are listed
+/// right before the instruction they point at.
+pub fn disassemble(function: &ByteCodeFunction) -> String {
+    let mut listing = String::new();
+    disassemble_function(&mut listing, function);
+    listing
+}
+
+fn disassemble_function(listing: &mut String, function: &ByteCodeFunction) {
+    writeln!(listing, "== {} ==", header(function)).unwrap();
+
+    let instructions =
+        encoding::decode(function).expect("functions are validated when they are built");
+    let mut targets: Vec<usize> = instructions
+        .iter()
+        .filter_map(|(_, bytecode)| jump_target(bytecode))
+        .collect();
+    targets.sort_unstable();
+    targets.dedup();
+    let write_label = |listing: &mut String, index: usize| {
+        if let Ok(label) = targets.binary_search(&index) {
+            writeln!(listing, "L{label}:").unwrap();
+        }
+    };
+
+    let mut previous_line = None;
+    for (index, (offset, bytecode)) in instructions.iter().enumerate() {
+        write_label(listing, index);
+        let line = function.lines.line(*offset);
+        if previous_line == Some(line) {
+            write!(listing, "{offset:04}    | ").unwrap();
+        } else {
+            write!(listing, "{offset:04} {line:>4} ").unwrap();
+        }
+        previous_line = Some(line);
+
+        let label = |target: &usize| targets.binary_search(target).unwrap();
+        let instruction = match bytecode {
+            ByteCode::Jump(target) => format!("Jump L{}", label(target)),
+            ByteCode::JumpIfFalse(target) => format!("JumpIfFalse L{}", label(target)),
+            ByteCode::JumpIfTrue(target) => format!("JumpIfTrue L{}", label(target)),
+            bytecode => format!("{bytecode:?}"),
+        };
+        writeln!(listing, "{instruction}").unwrap();
+    }
+    // Jumps past the last instruction leave the function.
+    write_label(listing, instructions.len());
+
+    for (_, bytecode) in instructions.iter() {
+        if let ByteCode::Closure(nested) = bytecode {
+            writeln!(listing).unwrap();
+            disassemble_function(listing, nested);
+        }
+    }
+}
+
+fn header(function: &ByteCodeFunction) -> String {
+    let mut header = function.name.clone();
+    write!(
+        header,
+        " (arity {}, registers {}",
+        function.arity, function.register_count
+    )
+    .unwrap();
+    if !function.upvalues.is_empty() {
+        header.push_str(", captures");
+        for upvalue in function.upvalues.iter() {
+            let kind = if upvalue.is_local { 'R' } else { 'U' };
+            write!(header, " {kind}{}", upvalue.index).unwrap();
+        }
+    }
+    header.push(')');
+    header
+}
+
+fn jump_target(bytecode: &ByteCode) -> Option<usize> {
+    match bytecode {
+        ByteCode::Jump(target) | ByteCode::JumpIfFalse(target) | ByteCode::JumpIfTrue(target) => {
+            Some(*target)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::ByteCodeGenerator;
+    use crate::parser::Parser;
+    use crate::resolver::Resolver;
+    use crate::scanner::Scanner;
+
+    fn disassemble_source(source: &str) -> String {
+        let tokens = Scanner::new(source.to_string()).scan_tokens();
+        let statements = Parser::new(&tokens).parse().unwrap();
+        Resolver::default().resolve(&statements).unwrap();
+        let mut generator = ByteCodeGenerator::default();
+        generator.intrepret(&statements).unwrap();
+        disassemble(&generator.script())
+    }
+
+    #[test]
+    fn test_listing_shows_offsets_lines_and_labels() {
+        let listing = disassemble_source(
+            "var i = 0;
+             while (i < 2) {
+               i = i + 1;
+             }
+             fun greet(name) { return \"hi \" + name; }",
+        );
+        assert_eq!(
+            listing,
+            "== script (arity 0, registers 2) ==
+0000    1 LdaConstant 0
+0002    | DefineGlobal i
+L0:
+0004    2 LdaGlobal i
+0006    | Star R1
+0008    | LdaConstant 2
+0010    | TestLessThan R1
+0012    | JumpIfFalse L1
+0014    3 LdaGlobal i
+0016    | Star R1
+0018    | LdaConstant 1
+0020    | Add R1
+0022    | StaGlobal i
+0024    | Jump L0
+L1:
+0026    5 Closure greet
+0028    | DefineGlobal greet
+
+== greet (arity 1, registers 3) ==
+0000    5 LdaConstant \"hi \"
+0002    | Star R2
+0004    | Ldar R1
+0006    | Add R2
+0008    | Return
+"
+        );
+    }
+}
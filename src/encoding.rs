@@ -0,0 +1,590 @@
+use std::rc::Rc;
+
+use crate::constant_pool::ConstantPool;
+use crate::function::ByteCodeFunction;
+use crate::generator::ByteCode;
+use crate::line_table::LineTable;
+use crate::value::Value;
+
+/*
+    instruction    → ( Wide | ExtraWide )? opcode operand* ;
+
+    Every operand of an instruction has the same width: one byte, or two
+    and four bytes after a `Wide` and an `ExtraWide` prefix. Operands are
+    registers, upvalue indices, indices into the function's constants or
+    nested functions, and jump targets, which are offsets into the code.
+    They are listed in the order of `ByteCode`'s fields, with names as
+    string constants and the operands of these spelled out:
+
+        Call           callee count argument*
+        Class          name has_superclass superclass
+*/
+
+/// Most registers a frame may have. Operands could address more, but the
+/// `Vm` allocates every frame up front.
+pub const MAX_REGISTERS: usize = 1 << 17;
+
+/// Numbering of the instructions in encoded code.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Opcode {
+    Wide,
+    ExtraWide,
+    Return,
+    Call,
+    Closure,
+    LdaUpvalue,
+    StaUpvalue,
+    CloseUpvalue,
+    Class,
+    Method,
+    GetProperty,
+    SetProperty,
+    GetSuper,
+    Ldar,
+    Star,
+    Move,
+    LdaUndefined,
+    LdaNil,
+    LdaConstant,
+    Add,
+    Mul,
+    Sub,
+    Div,
+    Equal,
+    NotEqual,
+    Negate,
+    LogicalNot,
+    LdaGlobal,
+    StaGlobal,
+    DefineGlobal,
+    Jump,
+    JumpIfFalse,
+    JumpIfTrue,
+    TestLessThan,
+    TestGreaterThan,
+    TestLessEqThan,
+    TestGreaterEqThan,
+    Print,
+}
+
+/// Every opcode, indexed by its byte.
+const OPCODES: [Opcode; 38] = [
+    Opcode::Wide,
+    Opcode::ExtraWide,
+    Opcode::Return,
+    Opcode::Call,
+    Opcode::Closure,
+    Opcode::LdaUpvalue,
+    Opcode::StaUpvalue,
+    Opcode::CloseUpvalue,
+    Opcode::Class,
+    Opcode::Method,
+    Opcode::GetProperty,
+    Opcode::SetProperty,
+    Opcode::GetSuper,
+    Opcode::Ldar,
+    Opcode::Star,
+    Opcode::Move,
+    Opcode::LdaUndefined,
+    Opcode::LdaNil,
+    Opcode::LdaConstant,
+    Opcode::Add,
+    Opcode::Mul,
+    Opcode::Sub,
+    Opcode::Div,
+    Opcode::Equal,
+    Opcode::NotEqual,
+    Opcode::Negate,
+    Opcode::LogicalNot,
+    Opcode::LdaGlobal,
+    Opcode::StaGlobal,
+    Opcode::DefineGlobal,
+    Opcode::Jump,
+    Opcode::JumpIfFalse,
+    Opcode::JumpIfTrue,
+    Opcode::TestLessThan,
+    Opcode::TestGreaterThan,
+    Opcode::TestLessEqThan,
+    Opcode::TestGreaterEqThan,
+    Opcode::Print,
+];
+
+impl Opcode {
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        OPCODES.get(byte as usize).copied()
+    }
+
+    /// Number of operands, not counting the arguments of a `Call`.
+    fn operand_count(self) -> usize {
+        match self {
+            Self::Wide
+            | Self::ExtraWide
+            | Self::Return
+            | Self::LdaUndefined
+            | Self::LdaNil
+            | Self::Negate
+            | Self::LogicalNot
+            | Self::Print => 0,
+            Self::Closure
+            | Self::LdaUpvalue
+            | Self::StaUpvalue
+            | Self::CloseUpvalue
+            | Self::GetProperty
+            | Self::GetSuper
+            | Self::Ldar
+            | Self::Star
+            | Self::LdaConstant
+            | Self::Add
+            | Self::Mul
+            | Self::Sub
+            | Self::Div
+            | Self::Equal
+            | Self::NotEqual
+            | Self::LdaGlobal
+            | Self::StaGlobal
+            | Self::DefineGlobal
+            | Self::Jump
+            | Self::JumpIfFalse
+            | Self::JumpIfTrue
+            | Self::TestLessThan
+            | Self::TestGreaterThan
+            | Self::TestLessEqThan
+            | Self::TestGreaterEqThan => 1,
+            Self::Call | Self::Method | Self::SetProperty | Self::Move => 2,
+            Self::Class => 3,
+        }
+    }
+}
+
+/// Reads the operand of `width` bytes at `offset`.
+pub fn read_operand(code: &[u8], offset: usize, width: usize) -> usize {
+    match width {
+        1 => code[offset] as usize,
+        2 => u16::from_le_bytes([code[offset], code[offset + 1]]) as usize,
+        _ => u32::from_le_bytes(code[offset..offset + 4].try_into().unwrap()) as usize,
+    }
+}
+
+/// Width of the operands of an instruction whose largest operand is `operand`.
+fn width(operand: usize) -> usize {
+    if operand <= u8::MAX as usize {
+        1
+    } else if operand <= u16::MAX as usize {
+        2
+    } else {
+        4
+    }
+}
+
+/// Encodes `bytecodes`, whose jumps target instruction indices, and
+/// returns the code together with the table of its lines.
+///
+/// `lines` maps instruction indices to lines, while the returned table maps
+/// code offsets. Names are added to `constants` and the functions of
+/// `Closure`s to `functions`.
+pub fn encode(
+    bytecodes: &[ByteCode],
+    lines: &LineTable,
+    constants: &mut ConstantPool,
+    functions: &mut Vec<Rc<ByteCodeFunction>>,
+) -> (Vec<u8>, LineTable) {
+    let instructions: Vec<(Opcode, Vec<usize>)> = bytecodes
+        .iter()
+        .map(|bytecode| split(bytecode, constants, functions))
+        .collect();
+
+    // A jump's width depends on the offset of its target, which in turn
+    // depends on the width of every instruction before it. Widths only
+    // ever grow, so widening jumps until nothing changes terminates.
+    let mut widths: Vec<usize> = instructions
+        .iter()
+        .map(|(opcode, operands)| match opcode {
+            Opcode::Jump | Opcode::JumpIfFalse | Opcode::JumpIfTrue => 1,
+            _ => width(operands.iter().copied().max().unwrap_or(0)),
+        })
+        .collect();
+    let offsets = loop {
+        let mut offsets = vec![0];
+        for ((_, operands), &width) in instructions.iter().zip(widths.iter()) {
+            let prefix = if width > 1 { 1 } else { 0 };
+            offsets.push(offsets.last().unwrap() + prefix + 1 + operands.len() * width);
+        }
+        let mut changed = false;
+        for (index, (opcode, operands)) in instructions.iter().enumerate() {
+            if let Opcode::Jump | Opcode::JumpIfFalse | Opcode::JumpIfTrue = opcode {
+                let needed = width(offsets[operands[0]]);
+                if needed > widths[index] {
+                    widths[index] = needed;
+                    changed = true;
+                }
+            }
+        }
+        if !changed {
+            break offsets;
+        }
+    };
+
+    let mut code = vec![];
+    let mut table = LineTable::default();
+    for (index, (opcode, operands)) in instructions.iter().enumerate() {
+        table.push(code.len(), lines.line(index));
+        let width = widths[index];
+        match width {
+            2 => code.push(Opcode::Wide as u8),
+            4 => code.push(Opcode::ExtraWide as u8),
+            _ => {}
+        }
+        code.push(*opcode as u8);
+        for &operand in operands {
+            let operand = match opcode {
+                Opcode::Jump | Opcode::JumpIfFalse | Opcode::JumpIfTrue => offsets[operand],
+                _ => operand,
+            };
+            code.extend(&(operand as u32).to_le_bytes()[..width]);
+        }
+    }
+    (code, table)
+}
+
+fn split(
+    bytecode: &ByteCode,
+    constants: &mut ConstantPool,
+    functions: &mut Vec<Rc<ByteCodeFunction>>,
+) -> (Opcode, Vec<usize>) {
+    let r = |register: &i32| *register as usize;
+    let mut name = |name: &String| constants.add(Value::String(name.clone()));
+    match bytecode {
+        ByteCode::Return => (Opcode::Return, vec![]),
+        ByteCode::Call(callee, arguments) => {
+            let mut operands = vec![r(callee), arguments.len()];
+            operands.extend(arguments.iter().map(r));
+            (Opcode::Call, operands)
+        }
+        ByteCode::Closure(function) => {
+            functions.push(function.clone());
+            (Opcode::Closure, vec![functions.len() - 1])
+        }
+        ByteCode::LdaUpvalue(index) => (Opcode::LdaUpvalue, vec![r(index)]),
+        ByteCode::StaUpvalue(index) => (Opcode::StaUpvalue, vec![r(index)]),
+        ByteCode::CloseUpvalue(register) => (Opcode::CloseUpvalue, vec![r(register)]),
+        ByteCode::Class(class, superclass) => {
+            let (has_superclass, superclass) = match superclass {
+                Some(superclass) => (1, r(superclass)),
+                None => (0, 0),
+            };
+            (Opcode::Class, vec![name(class), has_superclass, superclass])
+        }
+        ByteCode::Method(class, method) => (Opcode::Method, vec![r(class), name(method)]),
+        ByteCode::GetProperty(property) => (Opcode::GetProperty, vec![name(property)]),
+        ByteCode::SetProperty(object, property) => {
+            (Opcode::SetProperty, vec![r(object), name(property)])
+        }
+        ByteCode::GetSuper(method) => (Opcode::GetSuper, vec![name(method)]),
+        ByteCode::Ldar(src) => (Opcode::Ldar, vec![r(src)]),
+        ByteCode::Star(dst) => (Opcode::Star, vec![r(dst)]),
+        ByteCode::Move(dst, src) => (Opcode::Move, vec![r(dst), r(src)]),
+        ByteCode::LdaUndefined => (Opcode::LdaUndefined, vec![]),
+        ByteCode::LdaNil => (Opcode::LdaNil, vec![]),
+        ByteCode::LdaConstant(value) => (Opcode::LdaConstant, vec![constants.add(value.clone())]),
+        ByteCode::Add(src) => (Opcode::Add, vec![r(src)]),
+        ByteCode::Mul(src) => (Opcode::Mul, vec![r(src)]),
+        ByteCode::Sub(src) => (Opcode::Sub, vec![r(src)]),
+        ByteCode::Div(src) => (Opcode::Div, vec![r(src)]),
+        ByteCode::Equal(src) => (Opcode::Equal, vec![r(src)]),
+        ByteCode::NotEqual(src) => (Opcode::NotEqual, vec![r(src)]),
+        ByteCode::Negate => (Opcode::Negate, vec![]),
+        ByteCode::LogicalNot => (Opcode::LogicalNot, vec![]),
+        ByteCode::LdaGlobal(variable) => (Opcode::LdaGlobal, vec![name(variable)]),
+        ByteCode::StaGlobal(variable) => (Opcode::StaGlobal, vec![name(variable)]),
+        ByteCode::DefineGlobal(variable) => (Opcode::DefineGlobal, vec![name(variable)]),
+        ByteCode::Jump(target) => (Opcode::Jump, vec![*target]),
+        ByteCode::JumpIfFalse(target) => (Opcode::JumpIfFalse, vec![*target]),
+        ByteCode::JumpIfTrue(target) => (Opcode::JumpIfTrue, vec![*target]),
+        ByteCode::TestLessThan(src) => (Opcode::TestLessThan, vec![r(src)]),
+        ByteCode::TestGreaterThan(src) => (Opcode::TestGreaterThan, vec![r(src)]),
+        ByteCode::TestLessEqThan(src) => (Opcode::TestLessEqThan, vec![r(src)]),
+        ByteCode::TestGreaterEqThan(src) => (Opcode::TestGreaterEqThan, vec![r(src)]),
+        ByteCode::Print => (Opcode::Print, vec![]),
+    }
+}
+
+/// Decodes the code of `function` into `ByteCode`s paired with their code
+/// offsets. Jump targets become instruction indices, like the generator
+/// emits them.
+///
+/// Returns `None` unless the code is well formed and every operand is in
+/// range, so that the `Vm` can run it without further checks.
+pub fn decode(function: &ByteCodeFunction) -> Option<Vec<(usize, ByteCode)>> {
+    if function.register_count > MAX_REGISTERS {
+        return None;
+    }
+    let code = &function.code;
+    let mut instructions = vec![];
+    let mut offset = 0;
+    while offset < code.len() {
+        let start = offset;
+        let mut opcode = Opcode::from_byte(code[offset])?;
+        offset += 1;
+        let mut width = 1;
+        if let Opcode::Wide | Opcode::ExtraWide = opcode {
+            width = if opcode == Opcode::Wide { 2 } else { 4 };
+            opcode = Opcode::from_byte(*code.get(offset)?)?;
+            offset += 1;
+        }
+        if let Opcode::Wide | Opcode::ExtraWide = opcode {
+            return None;
+        }
+
+        let mut count = opcode.operand_count();
+        let mut operands = vec![];
+        while operands.len() < count {
+            code.get(offset..offset + width)?;
+            operands.push(read_operand(code, offset, width));
+            offset += width;
+            if opcode == Opcode::Call && operands.len() == 2 {
+                count += operands[1];
+            }
+        }
+        instructions.push((start, opcode, operands));
+    }
+
+    let offsets: Vec<usize> = instructions.iter().map(|(start, _, _)| *start).collect();
+    instructions
+        .into_iter()
+        .map(|(start, opcode, operands)| {
+            let operands = Operands {
+                function,
+                offsets: &offsets,
+                operands,
+            };
+            Some((start, operands.bytecode(opcode)?))
+        })
+        .collect()
+}
+
+/// Checks that a function with `register_count` registers and
+/// `upvalue_count` upvalues can create a closure of `function`. Calls put
+/// the receiver in R0 and the arguments after it, and closures capture
+/// from the frame and upvalues of their creator.
+pub fn check_closure(
+    function: &ByteCodeFunction,
+    register_count: usize,
+    upvalue_count: usize,
+) -> Result<(), String> {
+    if function.arity >= function.register_count {
+        return Err(format!(
+            "Function '{}' has no room for its {} parameters.",
+            function.name, function.arity
+        ));
+    }
+    for upvalue in function.upvalues.iter() {
+        let index = upvalue.index as usize;
+        if upvalue.is_local && index >= register_count {
+            return Err(format!(
+                "R{index} is outside of a frame of {register_count} registers."
+            ));
+        }
+        if !upvalue.is_local && index >= upvalue_count {
+            return Err(format!(
+                "U{index} is outside of the {upvalue_count} upvalues the function captures."
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The operands of one instruction, checked while they are turned back
+/// into the fields of a `ByteCode`.
+struct Operands<'a> {
+    function: &'a ByteCodeFunction,
+    offsets: &'a [usize],
+    operands: Vec<usize>,
+}
+
+impl Operands<'_> {
+    fn register(&self, index: usize) -> Option<i32> {
+        let register = self.operands[index];
+        (register < self.function.register_count).then_some(register as i32)
+    }
+
+    fn upvalue(&self, index: usize) -> Option<i32> {
+        let upvalue = self.operands[index];
+        (upvalue < self.function.upvalues.len()).then_some(upvalue as i32)
+    }
+
+    fn constant(&self, index: usize) -> Option<usize> {
+        let constant = self.operands[index];
+        (constant < self.function.constants.len()).then_some(constant)
+    }
+
+    fn string(&self, index: usize) -> Option<String> {
+        match self.function.constants.get(self.constant(index)?) {
+            Value::String(string) => Some(string.clone()),
+            _ => None,
+        }
+    }
+
+    fn target(&self) -> Option<usize> {
+        let target = self.operands[0];
+        if target == self.function.code.len() {
+            return Some(self.offsets.len());
+        }
+        self.offsets.binary_search(&target).ok()
+    }
+
+    fn bytecode(&self, opcode: Opcode) -> Option<ByteCode> {
+        let bytecode = match opcode {
+            Opcode::Wide | Opcode::ExtraWide => return None,
+            Opcode::Return => ByteCode::Return,
+            Opcode::Call => {
+                let arguments = (2..self.operands.len())
+                    .map(|index| self.register(index))
+                    .collect::<Option<_>>()?;
+                ByteCode::Call(self.register(0)?, arguments)
+            }
+            Opcode::Closure => {
+                let function = self.function.functions.get(self.operands[0])?;
+                ByteCode::Closure(function.clone())
+            }
+            Opcode::LdaUpvalue => ByteCode::LdaUpvalue(self.upvalue(0)?),
+            Opcode::StaUpvalue => ByteCode::StaUpvalue(self.upvalue(0)?),
+            Opcode::CloseUpvalue => ByteCode::CloseUpvalue(self.register(0)?),
+            Opcode::Class => {
+                let superclass = match self.operands[1] {
+                    0 => None,
+                    _ => Some(self.register(2)?),
+                };
+                ByteCode::Class(self.string(0)?, superclass)
+            }
+            Opcode::Method => ByteCode::Method(self.register(0)?, self.string(1)?),
+            Opcode::GetProperty => ByteCode::GetProperty(self.string(0)?),
+            Opcode::SetProperty => ByteCode::SetProperty(self.register(0)?, self.string(1)?),
+            Opcode::GetSuper => ByteCode::GetSuper(self.string(0)?),
+            Opcode::Ldar => ByteCode::Ldar(self.register(0)?),
+            Opcode::Star => ByteCode::Star(self.register(0)?),
+            Opcode::Move => ByteCode::Move(self.register(0)?, self.register(1)?),
+            Opcode::LdaUndefined => ByteCode::LdaUndefined,
+            Opcode::LdaNil => ByteCode::LdaNil,
+            Opcode::LdaConstant => {
+                ByteCode::LdaConstant(self.function.constants.get(self.constant(0)?).clone())
+            }
+            Opcode::Add => ByteCode::Add(self.register(0)?),
+            Opcode::Mul => ByteCode::Mul(self.register(0)?),
+            Opcode::Sub => ByteCode::Sub(self.register(0)?),
+            Opcode::Div => ByteCode::Div(self.register(0)?),
+            Opcode::Equal => ByteCode::Equal(self.register(0)?),
+            Opcode::NotEqual => ByteCode::NotEqual(self.register(0)?),
+            Opcode::Negate => ByteCode::Negate,
+            Opcode::LogicalNot => ByteCode::LogicalNot,
+            Opcode::LdaGlobal => ByteCode::LdaGlobal(self.string(0)?),
+            Opcode::StaGlobal => ByteCode::StaGlobal(self.string(0)?),
+            Opcode::DefineGlobal => ByteCode::DefineGlobal(self.string(0)?),
+            Opcode::Jump => ByteCode::Jump(self.target()?),
+            Opcode::JumpIfFalse => ByteCode::JumpIfFalse(self.target()?),
+            Opcode::JumpIfTrue => ByteCode::JumpIfTrue(self.target()?),
+            Opcode::TestLessThan => ByteCode::TestLessThan(self.register(0)?),
+            Opcode::TestGreaterThan => ByteCode::TestGreaterThan(self.register(0)?),
+            Opcode::TestLessEqThan => ByteCode::TestLessEqThan(self.register(0)?),
+            Opcode::TestGreaterEqThan => ByteCode::TestGreaterEqThan(self.register(0)?),
+            Opcode::Print => ByteCode::Print,
+        };
+        Some(bytecode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::function::UpvalueDescriptor;
+
+    fn function(register_count: usize, bytecodes: &[ByteCode]) -> ByteCodeFunction {
+        let mut lines = LineTable::default();
+        lines.push(0, 1);
+        ByteCodeFunction::new(
+            "test".to_string(),
+            0,
+            register_count,
+            bytecodes,
+            &lines,
+            vec![UpvalueDescriptor {
+                is_local: true,
+                index: 1,
+            }],
+        )
+    }
+
+    #[test]
+    fn test_opcodes_are_numbered_in_order() {
+        for (byte, opcode) in OPCODES.iter().enumerate() {
+            assert_eq!(*opcode as usize, byte);
+            assert_eq!(Opcode::from_byte(byte as u8), Some(*opcode));
+        }
+        assert_eq!(Opcode::from_byte(OPCODES.len() as u8), None);
+    }
+
+    #[test]
+    fn test_operands_widen_only_when_needed() {
+        let bytecodes = [
+            ByteCode::LdaConstant(Value::Number(1.0)),
+            ByteCode::Move(300, 1),
+            ByteCode::Add(70000),
+            ByteCode::LdaUpvalue(0),
+            ByteCode::JumpIfTrue(0),
+            ByteCode::Call(2, vec![1, 2]),
+        ];
+        let function = function(70001, &bytecodes);
+        assert_eq!(
+            function.code,
+            [
+                vec![Opcode::LdaConstant as u8, 0],
+                vec![Opcode::Wide as u8, Opcode::Move as u8, 44, 1, 1, 0],
+                vec![Opcode::ExtraWide as u8, Opcode::Add as u8, 112, 17, 1, 0],
+                vec![Opcode::LdaUpvalue as u8, 0],
+                vec![Opcode::JumpIfTrue as u8, 0],
+                vec![Opcode::Call as u8, 2, 2, 1, 2],
+            ]
+            .concat()
+        );
+
+        let decoded: Vec<_> = decode(&function).unwrap();
+        let offsets: Vec<_> = decoded.iter().map(|(offset, _)| *offset).collect();
+        assert_eq!(offsets, [0, 2, 8, 14, 16, 18]);
+        let decoded: Vec<_> = decoded.into_iter().map(|(_, bytecode)| bytecode).collect();
+        assert_eq!(format!("{decoded:?}"), format!("{bytecodes:?}"));
+    }
+
+    #[test]
+    fn test_jumps_widen_when_their_target_is_far() {
+        let mut bytecodes = vec![ByteCode::Jump(201)];
+        bytecodes.extend((0..200).map(|_| ByteCode::Move(1, 2)));
+        bytecodes.push(ByteCode::Return);
+        let function = function(3, &bytecodes);
+        // 1 + 200 * 3 bytes don't fit in a byte once the jump is widened.
+        assert_eq!(
+            function.code[..4],
+            [Opcode::Wide as u8, Opcode::Jump as u8, 92, 2]
+        );
+        assert_eq!(function.code.len(), 4 + 600 + 1);
+    }
+
+    #[test]
+    fn test_operands_out_of_range_are_rejected() {
+        assert!(decode(&function(2, &[ByteCode::Move(2, 1)])).is_none());
+        assert!(decode(&function(2, &[ByteCode::LdaUpvalue(1)])).is_none());
+
+        let number = ByteCode::LdaConstant(Value::Number(1.0));
+        let mut past_the_pool = function(2, std::slice::from_ref(&number));
+        past_the_pool.code[1] = 1;
+        assert!(decode(&past_the_pool).is_none());
+
+        let mut not_a_name = function(2, &[number, ByteCode::LdaGlobal("x".to_string())]);
+        // The number in constant 0 instead of the name.
+        not_a_name.code[3] = 0;
+        assert!(decode(&not_a_name).is_none());
+
+        let mut truncated = function(2, &[ByteCode::Move(1, 1)]);
+        truncated.code.pop();
+        assert!(decode(&truncated).is_none());
+    }
+}
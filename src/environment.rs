@@ -13,19 +13,54 @@ pub struct Environment {
 }
 
 impl Environment {
+    pub fn new(enclosing: Rc<RefCell<Environment>>) -> Self {
+        Self {
+            values: HashMap::new(),
+            enclosing: Some(enclosing),
+        }
+    }
+
     pub fn define(&mut self, name: String, value: Option<Value>) {
         self.values.insert(name, value);
     }
 
-    pub fn get(&mut self, name: Token) -> LoxResult<Option<Value>> {
-        if self.values.contains_key(&name.lexeme) {
-            return Ok(self.values.get(&name.lexeme).unwrap().clone());
+    /// Looks `name` up in this environment, then in each enclosing one.
+    pub fn get(&self, name: &Token) -> LoxResult<Option<Value>> {
+        if let Some(value) = self.values.get(&name.lexeme) {
+            return Ok(value.clone());
+        }
+
+        match &self.enclosing {
+            Some(enclosing) => enclosing.borrow().get(name),
+            None => Err(undefined_variable(name)),
+        }
+    }
+
+    /// Reads `name` from the environment `distance` hops up the chain, where
+    /// the `Resolver` found its declaration.
+    pub fn get_at(&self, distance: usize, name: &Token) -> LoxResult<Option<Value>> {
+        if distance > 0 {
+            let enclosing = self
+                .enclosing
+                .as_ref()
+                .expect("resolved past the outermost scope");
+            return enclosing.borrow().get_at(distance - 1, name);
+        }
+        match self.values.get(&name.lexeme) {
+            Some(value) => Ok(value.clone()),
+            None => Err(undefined_variable(name)),
         }
+    }
 
-        Err(LoxErrors::RunTimeException(Error::new(
-            name.line,
-            format!("Undefined variable {} .", name.lexeme),
-        )))
+    pub fn assign_at(&mut self, distance: usize, name: Token, value: Option<Value>) {
+        if distance > 0 {
+            let enclosing = self
+                .enclosing
+                .as_ref()
+                .expect("resolved past the outermost scope");
+            return enclosing.borrow_mut().assign_at(distance - 1, name, value);
+        }
+        self.values.insert(name.lexeme, value);
     }
 
     pub fn assign(&mut self, name: Token, value: Option<Value>) -> LoxResult<()> {
@@ -33,13 +68,17 @@ impl Environment {
             if let Some(enclosing) = &mut self.enclosing {
                 return enclosing.borrow_mut().assign(name, value);
             } else {
-                return Err(LoxErrors::RunTimeException(Error::new(
-                    name.line,
-                    format!("Undefined variable {} .", name.lexeme),
-                )));
+                return Err(undefined_variable(&name));
             }
         }
         self.values.insert(name.lexeme, value);
         Ok(())
     }
 }
+
+fn undefined_variable(name: &Token) -> LoxErrors {
+    LoxErrors::RunTimeException(Error::new(
+        name.line,
+        format!("Undefined variable '{}'.", name.lexeme),
+    ))
+}
@@ -1,3 +1,7 @@
+use std::fmt;
+
+use crate::value::Value;
+
 #[derive(Debug, Clone)]
 pub struct Error {
     line: usize,
@@ -10,9 +14,9 @@ impl Error {
     }
 }
 
-impl ToString for Error {
-    fn to_string(&self) -> String {
-        format!("Error occured at line {}: {}", self.line, self.message)
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Error occured at line {}: {}", self.line, self.message)
     }
 }
 
@@ -20,20 +24,27 @@ impl ToString for Error {
 pub enum LoxErrors {
     ParseError(Error),
     RunTimeException(Error),
+    /// A compiled script that can't be loaded.
+    InvalidBytecode(String),
+    /// Not a real error: unwinds the tree-walker out of a function body when
+    /// a `return` statement is executed.
+    Return(Option<Value>),
 }
 
-impl ToString for LoxErrors {
-    fn to_string(&self) -> String {
+impl fmt::Display for LoxErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            LoxErrors::ParseError(error) => error.to_string(),
-            LoxErrors::RunTimeException(error) => error.to_string(),
+            LoxErrors::ParseError(error) => write!(f, "{error}"),
+            LoxErrors::RunTimeException(error) => write!(f, "{error}"),
+            LoxErrors::InvalidBytecode(message) => write!(f, "Invalid bytecode file: {message}."),
+            LoxErrors::Return(_) => write!(f, "Can't return from top-level code."),
         }
     }
 }
 
 impl LoxErrors {
     pub fn report(&self) {
-        eprintln!("{}", self.to_string());
+        eprintln!("{}", self);
     }
 }
 
@@ -0,0 +1,187 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::{Rc, Weak};
+
+use crate::ast::FunctionStmt;
+use crate::class::LoxClass;
+use crate::constant_pool::ConstantPool;
+use crate::encoding;
+use crate::environment::Environment;
+use crate::generator::ByteCode;
+use crate::line_table::LineTable;
+use crate::value::Value;
+
+/// A function declared in source and executed by the tree-walking `Intrepreter`.
+pub struct LoxFunction {
+    pub declaration: FunctionStmt,
+    /// The environment the function was declared in.
+    pub closure: Rc<RefCell<Environment>>,
+    pub is_initializer: bool,
+}
+
+impl LoxFunction {
+    pub fn new(
+        declaration: FunctionStmt,
+        closure: Rc<RefCell<Environment>>,
+        is_initializer: bool,
+    ) -> Self {
+        Self {
+            declaration,
+            closure,
+            is_initializer,
+        }
+    }
+
+    /// Returns a copy of this method whose body sees `this` as `instance`.
+    pub fn bind(&self, instance: Value) -> Self {
+        let mut environment = Environment::new(self.closure.clone());
+        environment.define("this".to_string(), Some(instance));
+        Self::new(
+            self.declaration.clone(),
+            Rc::new(RefCell::new(environment)),
+            self.is_initializer,
+        )
+    }
+
+    pub fn arity(&self) -> usize {
+        self.declaration.params.len()
+    }
+}
+
+impl fmt::Debug for LoxFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<fn {}>", self.declaration.name.lexeme)
+    }
+}
+
+impl PartialEq for LoxFunction {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+/// Where a closure finds one of its captured variables when it is created.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct UpvalueDescriptor {
+    /// `true` if the variable is a local in the register `index` of the
+    /// enclosing frame, `false` if it is the enclosing closure's upvalue `index`.
+    pub is_local: bool,
+    pub index: i32,
+}
+
+/// A function compiled by `ByteCodeGenerator` and executed by the `Vm`.
+///
+/// Parameters occupy registers `R1..=Rn` of the frame created for each call.
+pub struct ByteCodeFunction {
+    pub name: String,
+    pub arity: usize,
+    /// Size of the register window a call to this function needs, R0 included.
+    pub register_count: usize,
+    /// Instructions in the format described in `encoding`.
+    pub code: Vec<u8>,
+    /// Source line of the instruction at each offset of `code`.
+    pub lines: LineTable,
+    /// Literals and names the instructions refer to.
+    pub constants: ConstantPool,
+    /// Functions created by the `Closure` instructions.
+    pub functions: Vec<Rc<ByteCodeFunction>>,
+    pub upvalues: Vec<UpvalueDescriptor>,
+}
+
+impl ByteCodeFunction {
+    /// Encodes `bytecodes`, where `lines` holds the line of each
+    /// instruction by index.
+    pub fn new(
+        name: String,
+        arity: usize,
+        register_count: usize,
+        bytecodes: &[ByteCode],
+        lines: &LineTable,
+        upvalues: Vec<UpvalueDescriptor>,
+    ) -> Self {
+        let mut constants = ConstantPool::default();
+        let mut functions = vec![];
+        let (code, lines) = encoding::encode(bytecodes, lines, &mut constants, &mut functions);
+        Self {
+            name,
+            arity,
+            register_count,
+            code,
+            lines,
+            constants,
+            functions,
+            upvalues,
+        }
+    }
+}
+
+impl fmt::Debug for ByteCodeFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<fn {}>", self.name)
+    }
+}
+
+impl PartialEq for ByteCodeFunction {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+/// A variable captured by a closure. It points into the registers of the
+/// declaring frame while that frame is live and owns the value once the
+/// variable goes out of scope.
+#[derive(Debug)]
+pub enum Upvalue {
+    Open { frame: usize, register: i32 },
+    Closed(Value),
+}
+
+/// A `ByteCodeFunction` paired with the variables it captured at runtime.
+pub struct Closure {
+    pub function: Rc<ByteCodeFunction>,
+    pub upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    /// The class a method was defined in, which `super` lookups start from.
+    /// Closures created inside a method inherit it.
+    pub home: Option<Weak<LoxClass>>,
+}
+
+impl Closure {
+    pub fn new(function: Rc<ByteCodeFunction>) -> Self {
+        Self {
+            function,
+            upvalues: vec![],
+            home: None,
+        }
+    }
+}
+
+impl fmt::Debug for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.function)
+    }
+}
+
+impl PartialEq for Closure {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+/// A method closure together with the instance it was accessed on. The
+/// receiver is passed to the method in register `R0`.
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: Rc<Closure>,
+}
+
+impl fmt::Debug for BoundMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.method)
+    }
+}
+
+impl PartialEq for BoundMethod {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
@@ -1,11 +1,14 @@
-use std::collections::HashMap;
+use std::rc::Rc;
 
 use crate::ast::{
-    Assign, Binary, BlockStmt, Expr, ExpressionStmt, Grouping, IfStmt, Literal as LiteralExp,
-    Logical, PrintStmt, Stmt, Unary, VarStmt, Variable, VisitorExpr, VisitorStmt, WhileStmt,
+    Assign, Binary, BlockStmt, Call, ClassStmt, Expr, ExpressionStmt, FunctionStmt, Get, Grouping,
+    IfStmt, Literal as LiteralExp, Logical, PrintStmt, ReturnStmt, Set, Stmt, Super, This, Unary,
+    VarStmt, Variable, VisitorExpr, VisitorStmt, WhileStmt,
 };
+use crate::encoding::MAX_REGISTERS;
 use crate::error::{Error, LoxErrors, LoxResult};
-use crate::token::Token;
+use crate::function::{ByteCodeFunction, UpvalueDescriptor};
+use crate::line_table::LineTable;
 
 use crate::token_type::TokenType::{
     self, Bang, BangEqual, EqualEqual, Greater, GreaterEqual, Less, LessEqual, Minus, Plus, Slash,
@@ -14,162 +17,621 @@ use crate::token_type::TokenType::{
 use crate::tools::*;
 
 use crate::value::Value;
-type Literal = Option<Value>;
 
+/// An instruction as the generator emits it and the disassembler shows it.
+/// Functions store their instructions encoded as described in `encoding`.
+///
+/// Like V8's Ignition, most instructions read one of their operands from
+/// the accumulator, a register of the `Vm` that isn't part of any frame,
+/// and leave their result in it. Binary operators take their left operand
+/// from a register and their right operand from the accumulator.
 #[derive(Clone)]
 pub enum ByteCode {
+    /// Returns the accumulator to the caller.
     Return,
-    LoadUndefined(i32),
-    NewString(i32, String),
-    Add(i32, i32, i32),
-    Mul(i32, i32, i32),
-    Sub(i32, i32, i32),
-    Div(i32, i32, i32),
-    SetVariable(String, i32),
-    GetVariable(String, i32),
-    Load(i32, Value),
-    JumpIfTrue(String),
-    TestLessThan(i32, i32),
-    TestGreaterThan(i32, i32),
-    TestLessEqThan(i32, i32),
-    TestGreaterEqThan(i32, i32),
-    Label(String),
-    Print(i32),
+    /// Calls the function in a register with the arguments in the others.
+    Call(i32, Vec<i32>),
+    Closure(Rc<ByteCodeFunction>),
+    LdaUpvalue(i32),
+    StaUpvalue(i32),
+    CloseUpvalue(i32),
+    /// Creates a class, inheriting from the class in the register if any.
+    Class(String, Option<i32>),
+    /// Adds the closure in the accumulator to the class in the register.
+    Method(i32, String),
+    GetProperty(String),
+    /// Sets a property of the instance in the register to the accumulator.
+    SetProperty(i32, String),
+    /// Binds a method of the superclass to the instance in the accumulator.
+    GetSuper(String),
+    /// Loads a register into the accumulator.
+    Ldar(i32),
+    /// Stores the accumulator into a register.
+    Star(i32),
+    Move(i32, i32),
+    LdaUndefined,
+    LdaNil,
+    /// Loads a literal, which is kept in the function's `ConstantPool`.
+    LdaConstant(Value),
+    Add(i32),
+    Mul(i32),
+    Sub(i32),
+    Div(i32),
+    Equal(i32),
+    NotEqual(i32),
+    Negate,
+    LogicalNot,
+    LdaGlobal(String),
+    /// Assigns to an existing global; `DefineGlobal` creates one.
+    StaGlobal(String),
+    DefineGlobal(String),
+    /// Jumps hold a label id while their function is being generated and
+    /// the instruction offset it resolves to once `patch_jumps` has run.
+    /// Conditional jumps test the truthiness of the accumulator.
+    Jump(usize),
+    JumpIfFalse(usize),
+    JumpIfTrue(usize),
+    /// Comparisons load the boolean they produce into the accumulator.
+    TestLessThan(i32),
+    TestGreaterThan(i32),
+    TestLessEqThan(i32),
+    TestGreaterEqThan(i32),
+    Print,
 }
 
+/// Every instruction is printed as its name followed by its operands,
+/// separated by spaces: registers as `R1`, upvalues as `U0`, jump targets
+/// as instruction offsets and strings quoted.
 impl std::fmt::Debug for ByteCode {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Return => write!(f, "Return"),
-            Self::LoadUndefined(reg) => write!(f, "LoadUndefined R{:?}", reg),
-            Self::Load(reg, value) => write!(f, "Load R{:?} {}", reg, value),
-            Self::Add(dst, src1, src2) => {
-                write!(f, "Add R{:?}, R{:?}, R{:?}", dst, src1, src2)
+            Self::Call(callee, args) => {
+                write!(f, "Call R{callee}")?;
+                args.iter().try_for_each(|arg| write!(f, " R{arg}"))
             }
-            Self::Mul(dst, src1, src2) => write!(f, "Mul Reg {} {:?}, {:?}", dst, src1, src2),
-            Self::Sub(dst, src1, src2) => write!(f, "Sub Reg {} {:?}, {:?}", dst, src1, src2),
-            Self::Div(dst, src1, src2) => write!(f, "Div Reg {} {:?}, {:?}", dst, src1, src2),
-            Self::NewString(dst, src) => write!(f, "NewString Reg {} {}", dst, src),
-            Self::SetVariable(name, reg) => write!(f, "SetVariable {} R{:?}", name, reg),
-            Self::GetVariable(name, reg) => write!(f, "GetVariable R{} {:?}", reg, name),
-            Self::JumpIfTrue(label) => write!(f, "JumpIfTrue {:?}", label),
-            Self::TestLessThan(src1, src2) => write!(f, "TestLessThan R{:?}, R{:?}", src1, src2),
-            Self::TestGreaterThan(src1, src2) => {
-                write!(f, "TestGreaterThan R{:?}, R{:?}", src1, src2)
-            }
-            Self::TestLessEqThan(src1, src2) => {
-                write!(f, "TestLessEqThan R{:?}, R{:?}", src1, src2)
-            }
-            Self::TestGreaterEqThan(src1, src2) => {
-                write!(f, "TestGreaterEqThan R{:?}, R{:?}", src1, src2)
-            }
-            Self::Label(label) => write!(f, "{}:", label),
-            Self::Print(value) => write!(f, "Print R{:?}", value),
+            Self::Closure(function) => write!(f, "Closure {}", function.name),
+            Self::LdaUpvalue(index) => write!(f, "LdaUpvalue U{index}"),
+            Self::StaUpvalue(index) => write!(f, "StaUpvalue U{index}"),
+            Self::CloseUpvalue(reg) => write!(f, "CloseUpvalue R{reg}"),
+            Self::Class(name, None) => write!(f, "Class {name}"),
+            Self::Class(name, Some(superclass)) => write!(f, "Class {name} R{superclass}"),
+            Self::Method(class, name) => write!(f, "Method R{class} {name}"),
+            Self::GetProperty(name) => write!(f, "GetProperty {name}"),
+            Self::SetProperty(object, name) => write!(f, "SetProperty R{object} {name}"),
+            Self::GetSuper(name) => write!(f, "GetSuper {name}"),
+            Self::Ldar(src) => write!(f, "Ldar R{src}"),
+            Self::Star(dst) => write!(f, "Star R{dst}"),
+            Self::Move(dst, src) => write!(f, "Move R{dst} R{src}"),
+            Self::LdaUndefined => write!(f, "LdaUndefined"),
+            Self::LdaNil => write!(f, "LdaNil"),
+            Self::LdaConstant(Value::String(string)) => write!(f, "LdaConstant {string:?}"),
+            Self::LdaConstant(value) => write!(f, "LdaConstant {value}"),
+            Self::Add(src) => write!(f, "Add R{src}"),
+            Self::Mul(src) => write!(f, "Mul R{src}"),
+            Self::Sub(src) => write!(f, "Sub R{src}"),
+            Self::Div(src) => write!(f, "Div R{src}"),
+            Self::Equal(src) => write!(f, "Equal R{src}"),
+            Self::NotEqual(src) => write!(f, "NotEqual R{src}"),
+            Self::Negate => write!(f, "Negate"),
+            Self::LogicalNot => write!(f, "LogicalNot"),
+            Self::LdaGlobal(name) => write!(f, "LdaGlobal {name}"),
+            Self::StaGlobal(name) => write!(f, "StaGlobal {name}"),
+            Self::DefineGlobal(name) => write!(f, "DefineGlobal {name}"),
+            Self::Jump(target) => write!(f, "Jump {target}"),
+            Self::JumpIfFalse(target) => write!(f, "JumpIfFalse {target}"),
+            Self::JumpIfTrue(target) => write!(f, "JumpIfTrue {target}"),
+            Self::TestLessThan(src) => write!(f, "TestLessThan R{src}"),
+            Self::TestGreaterThan(src) => write!(f, "TestGreaterThan R{src}"),
+            Self::TestLessEqThan(src) => write!(f, "TestLessEqThan R{src}"),
+            Self::TestGreaterEqThan(src) => write!(f, "TestGreaterEqThan R{src}"),
+            Self::Print => write!(f, "Print"),
         }
     }
 }
 
+#[derive(Clone, Copy, Default, PartialEq)]
+enum FunctionKind {
+    #[default]
+    Script,
+    Function,
+    Method,
+    Initializer,
+}
+
+/// A variable declared inside a block or function body. It lives in a
+/// register of the frame instead of the VM's global table.
+struct Local {
+    name: String,
+    /// Scope depth of the block that declared it.
+    depth: usize,
+    register: i32,
+    /// Set once a nested closure captures it, so it has to be closed when
+    /// its scope ends.
+    is_captured: bool,
+}
+
+/// Per-function compilation state, saved while a nested function body is
+/// being generated.
+struct FunctionState {
+    kind: FunctionKind,
+    bytecodes: Vec<ByteCode>,
+    lines: LineTable,
+    register_count: usize,
+    frame_size: usize,
+    locals: Vec<Local>,
+    scope_depth: usize,
+    upvalues: Vec<UpvalueDescriptor>,
+    labels: Vec<Option<usize>>,
+    jumps: Vec<usize>,
+}
+
+/// How a name used inside a function body is reached at runtime.
+enum VariableKind {
+    Local(i32),
+    Upvalue(i32),
+    Global,
+}
+
 #[derive(Default)]
 pub struct ByteCodeGenerator {
     pub bytecodes: Vec<ByteCode>,
+    /// Source line of each instruction in `bytecodes`.
+    pub lines: LineTable,
+    /// Line of the node being generated, recorded for every emitted instruction.
+    line: usize,
+    kind: FunctionKind,
+    /// Registers are handed out like a stack: locals sit at the bottom in
+    /// declaration order and temporaries are pushed above them.
     register_count: usize,
-    label_count: usize,
-    variables: HashMap<String, i32>,
-    values: HashMap<String, Value>,
+    /// Registers the frame of the function being generated needs, R0
+    /// included: one more than the highest register ever allocated.
+    pub frame_size: usize,
+    /// Instruction offset each label is bound to, indexed by label id.
+    labels: Vec<Option<usize>>,
+    /// Offsets of the jumps whose label still has to be patched in.
+    jumps: Vec<usize>,
+    /// Locals in scope in the current function, innermost last.
+    locals: Vec<Local>,
+    /// Number of blocks enclosing the code being generated. Declarations at
+    /// depth 0 are globals; everything deeper gets a register.
+    scope_depth: usize,
+    /// Variables of enclosing functions captured by the current function.
+    upvalues: Vec<UpvalueDescriptor>,
+    enclosing: Vec<FunctionState>,
 }
 
 impl ByteCodeGenerator {
-    fn evaluate(&mut self, expr: &Expr) -> LoxResult<Literal> {
+    /// Generates `expr`, which leaves its value in the accumulator.
+    fn evaluate(&mut self, expr: &Expr) -> LoxResult<()> {
         expr.accept(self)
     }
 
-    fn allocate_label(&mut self) -> String {
-        self.label_count += 1;
-        format!("L{}", self.label_count)
+    /// Generates `expr` into a register for an instruction that takes it
+    /// as a register operand, with `later` the operands evaluated after it.
+    /// A local is used in place unless one of them may assign it; anything
+    /// else is stored into a fresh temporary.
+    fn evaluate_to_register(&mut self, expr: &Expr, later: &[&Expr]) -> LoxResult<i32> {
+        if let Some(register) = self.local_operand(expr) {
+            if !later.iter().any(|operand| may_assign(operand, expr)) {
+                return Ok(register);
+            }
+        }
+        self.evaluate(expr)?;
+        let register = self.allocate_register();
+        self.emit_bytecode(ByteCode::Star(register));
+        Ok(register)
+    }
+
+    /// The home register of the local that `expr` reads, if it reads one.
+    fn local_operand(&mut self, expr: &Expr) -> Option<i32> {
+        let (name, depth) = match expr {
+            Expr::Variable(variable) => (variable.name.lexeme.as_str(), variable.depth.get()),
+            Expr::This(this) => ("this", this.depth.get()),
+            _ => return None,
+        };
+        match self.resolve_variable(name, depth) {
+            VariableKind::Local(register) => Some(register),
+            _ => None,
+        }
+    }
+
+    fn allocate_label(&mut self) -> usize {
+        self.labels.push(None);
+        self.labels.len() - 1
+    }
+
+    /// Binds `label` to the offset of the next emitted instruction.
+    fn bind_label(&mut self, label: usize) {
+        self.labels[label] = Some(self.bytecodes.len());
+    }
+
+    fn emit_jump(&mut self, jump: fn(usize) -> ByteCode, label: usize) {
+        self.jumps.push(self.bytecodes.len());
+        self.emit_bytecode(jump(label));
+    }
+
+    /// Rewrites the label id of every jump emitted in the current function
+    /// into the instruction offset the label was bound to.
+    fn patch_jumps(&mut self) {
+        for offset in std::mem::take(&mut self.jumps) {
+            match &mut self.bytecodes[offset] {
+                ByteCode::Jump(target)
+                | ByteCode::JumpIfFalse(target)
+                | ByteCode::JumpIfTrue(target) => {
+                    *target = self.labels[*target].expect("jump to an unbound label");
+                }
+                _ => unreachable!("only jumps are patched"),
+            }
+        }
     }
 
     pub fn intrepret(&mut self, statements: &[Stmt]) -> LoxResult<()> {
         for stmt in statements.iter() {
             self.execute(stmt)?
         }
+        self.patch_jumps();
         Ok(())
     }
 
-    fn execute_block(&mut self, statements: &Vec<Stmt>) -> LoxResult<()> {
+    /// Packages the generated top-level code as the function the `Vm`
+    /// starts executing.
+    pub fn script(self) -> ByteCodeFunction {
+        ByteCodeFunction::new(
+            "script".to_string(),
+            0,
+            self.frame_size,
+            &self.bytecodes,
+            &self.lines,
+            vec![],
+        )
+    }
+
+    fn execute_block(&mut self, statements: &[Stmt]) -> LoxResult<()> {
         statements.iter().try_for_each(|stmt| self.execute(stmt))?;
         Ok(())
     }
 
-    fn current_register(&self) -> i32 {
-        self.register_count as i32
+    /// Classifies `name` as seen from the function currently being generated.
+    /// `depth` is what the `Resolver` recorded for this use; names it left
+    /// unresolved are globals.
+    fn resolve_variable(&mut self, name: &str, depth: Option<usize>) -> VariableKind {
+        if depth.is_none() {
+            return VariableKind::Global;
+        }
+        if let Some(local) = self.locals.iter().rev().find(|local| local.name == name) {
+            return VariableKind::Local(local.register);
+        }
+        match self.resolve_upvalue(self.enclosing.len(), name) {
+            Some(index) => VariableKind::Upvalue(index),
+            None => VariableKind::Global,
+        }
+    }
+
+    /// Finds `name` among the locals of the functions enclosing the one at
+    /// `depth` and threads it through every function in between as an upvalue.
+    /// Depth 0 is the top-level script, which has no enclosing function.
+    fn resolve_upvalue(&mut self, depth: usize, name: &str) -> Option<i32> {
+        if depth == 0 {
+            return None;
+        }
+        let enclosing = &mut self.enclosing[depth - 1];
+        let local = enclosing
+            .locals
+            .iter_mut()
+            .rev()
+            .find(|local| local.name == name);
+        let descriptor = match local {
+            Some(local) => {
+                local.is_captured = true;
+                UpvalueDescriptor {
+                    is_local: true,
+                    index: local.register,
+                }
+            }
+            None => UpvalueDescriptor {
+                is_local: false,
+                index: self.resolve_upvalue(depth - 1, name)?,
+            },
+        };
+
+        let upvalues = if depth == self.enclosing.len() {
+            &mut self.upvalues
+        } else {
+            &mut self.enclosing[depth].upvalues
+        };
+        let index = match upvalues.iter().position(|upvalue| *upvalue == descriptor) {
+            Some(index) => index,
+            None => {
+                upvalues.push(descriptor);
+                upvalues.len() - 1
+            }
+        };
+        Some(index as i32)
+    }
+
+    fn compile_function(
+        &mut self,
+        stmt: &FunctionStmt,
+        kind: FunctionKind,
+    ) -> LoxResult<ByteCodeFunction> {
+        self.enclosing.push(FunctionState {
+            kind: std::mem::replace(&mut self.kind, kind),
+            bytecodes: std::mem::take(&mut self.bytecodes),
+            lines: std::mem::take(&mut self.lines),
+            register_count: std::mem::take(&mut self.register_count),
+            frame_size: std::mem::replace(&mut self.frame_size, 1),
+            locals: std::mem::take(&mut self.locals),
+            // Parameters and the body share the function's outermost scope.
+            scope_depth: std::mem::replace(&mut self.scope_depth, 1),
+            upvalues: std::mem::take(&mut self.upvalues),
+            labels: std::mem::take(&mut self.labels),
+            jumps: std::mem::take(&mut self.jumps),
+        });
+
+        if kind != FunctionKind::Function {
+            // Methods receive their instance in R0.
+            self.locals.push(Local {
+                name: "this".to_string(),
+                depth: self.scope_depth,
+                register: 0,
+                is_captured: false,
+            });
+        }
+        for param in stmt.params.iter() {
+            self.declare_local(&param.lexeme);
+        }
+        let result = self.execute_block(&stmt.body);
+        // A body ending in a `return` never reaches the implicit one.
+        if !matches!(self.bytecodes.last(), Some(ByteCode::Return)) || self.is_jump_target() {
+            self.emit_return();
+        }
+        self.patch_jumps();
+
+        let state = self.enclosing.pop().unwrap();
+        self.kind = state.kind;
+        let bytecodes = std::mem::replace(&mut self.bytecodes, state.bytecodes);
+        let lines = std::mem::replace(&mut self.lines, state.lines);
+        let upvalues = std::mem::replace(&mut self.upvalues, state.upvalues);
+        self.register_count = state.register_count;
+        let frame_size = std::mem::replace(&mut self.frame_size, state.frame_size);
+        self.locals = state.locals;
+        self.scope_depth = state.scope_depth;
+        self.labels = state.labels;
+        self.jumps = state.jumps;
+        // The closure is created where the function is declared.
+        self.line = stmt.name.line;
+        result?;
+
+        Ok(ByteCodeFunction::new(
+            stmt.name.lexeme.clone(),
+            stmt.params.len(),
+            frame_size,
+            &bytecodes,
+            &lines,
+            upvalues,
+        ))
     }
 
     fn execute(&mut self, stmt: &Stmt) -> LoxResult<()> {
-        stmt.accept(self)
+        let result = stmt.accept(self);
+        // No temporary outlives the statement that needed it.
+        self.release_registers(self.local_count());
+        result?;
+        if self.frame_size > MAX_REGISTERS {
+            return Err(LoxErrors::ParseError(Error::new(
+                self.line,
+                format!("Function needs more than {MAX_REGISTERS} registers."),
+            )));
+        }
+        Ok(())
     }
 
     fn emit_bytecode(&mut self, bytecode: ByteCode) {
+        // The accumulator still holds what was just stored.
+        if let (ByteCode::Ldar(src), Some(ByteCode::Star(dst))) = (&bytecode, self.bytecodes.last())
+        {
+            if src == dst && !self.is_jump_target() {
+                return;
+            }
+        }
+        self.lines.push(self.bytecodes.len(), self.line);
         self.bytecodes.push(bytecode);
     }
 
+    /// Whether a label is bound to the next instruction, which can then be
+    /// reached other than from the one before it.
+    fn is_jump_target(&self) -> bool {
+        self.labels.contains(&Some(self.bytecodes.len()))
+    }
+
+    fn emit_return(&mut self) {
+        if self.kind == FunctionKind::Initializer {
+            self.emit_bytecode(ByteCode::Ldar(0));
+        } else {
+            self.emit_bytecode(ByteCode::LdaNil);
+        }
+        self.emit_bytecode(ByteCode::Return);
+    }
+
+    /// Loads the variable `name` into the accumulator.
+    fn load_variable(&mut self, name: &str, depth: Option<usize>) {
+        match self.resolve_variable(name, depth) {
+            VariableKind::Local(register) => self.emit_bytecode(ByteCode::Ldar(register)),
+            VariableKind::Upvalue(index) => self.emit_bytecode(ByteCode::LdaUpvalue(index)),
+            VariableKind::Global => self.emit_bytecode(ByteCode::LdaGlobal(name.to_string())),
+        }
+    }
+
+    /// Returns a register holding the variable `name`: its home register if
+    /// it is a local, otherwise a temporary it is loaded into.
+    fn variable_register(&mut self, name: &str, depth: Option<usize>) -> i32 {
+        if let VariableKind::Local(register) = self.resolve_variable(name, depth) {
+            return register;
+        }
+        self.load_variable(name, depth);
+        let register = self.allocate_register();
+        self.emit_bytecode(ByteCode::Star(register));
+        register
+    }
+
+    /// Evaluates `condition` into the accumulator, whose truthiness a
+    /// following conditional jump tests.
+    fn emit_condition(&mut self, condition: &Expr) -> LoxResult<()> {
+        self.evaluate(condition)?;
+        // Only the accumulator is needed from here on.
+        self.release_registers(self.local_count());
+        Ok(())
+    }
+
+    /// Pushes a fresh register. R0 is reserved for a method's receiver, so
+    /// the first register handed out is R1.
     fn allocate_register(&mut self) -> i32 {
         self.register_count += 1;
+        self.frame_size = self.frame_size.max(self.register_count + 1);
         self.register_count as i32
     }
+
+    /// Frees every register above `register`.
+    fn release_registers(&mut self, register: i32) {
+        self.register_count = register as usize;
+    }
+
+    /// Highest register holding a local; temporaries are allocated above it.
+    fn local_count(&self) -> i32 {
+        self.locals.last().map_or(0, |local| local.register)
+    }
+
+    /// Pins the next register as the home of local `name`. Locals are only
+    /// declared once the temporaries of their statement have been released.
+    fn declare_local(&mut self, name: &str) -> i32 {
+        let register = self.allocate_register();
+        self.locals.push(Local {
+            name: name.to_string(),
+            depth: self.scope_depth,
+            register,
+            is_captured: false,
+        });
+        register
+    }
+
+    /// Leaves the innermost block: its locals go out of scope, the ones a
+    /// closure captured are closed, and their registers become free again.
+    fn end_scope(&mut self) {
+        self.scope_depth -= 1;
+        while let Some(local) = self.locals.last() {
+            if local.depth <= self.scope_depth {
+                break;
+            }
+            let local = self.locals.pop().unwrap();
+            if local.is_captured {
+                self.emit_bytecode(ByteCode::CloseUpvalue(local.register));
+            }
+        }
+        self.release_registers(self.local_count());
+    }
 }
 
 impl VisitorExpr for ByteCodeGenerator {
-    type Result = LoxResult<Literal>;
+    type Result = LoxResult<()>;
 
     fn visit_logical_expr(&mut self, expr: &Logical) -> Self::Result {
-        let left = self.evaluate(&expr.left)?;
-
-        if let Some(left) = left {
-            if expr.operator.type_ == TokenType::Or && is_truthy(&left) {
-                return Ok(Some(left));
-            }
+        self.evaluate(&expr.left)?;
+        self.line = expr.operator.line;
+
+        // `or` keeps a truthy left operand, `and` keeps a falsy one, and
+        // either way it is already in the accumulator.
+        let end_label = self.allocate_label();
+        if expr.operator.type_ == TokenType::Or {
+            self.emit_jump(ByteCode::JumpIfTrue, end_label);
+        } else {
+            self.emit_jump(ByteCode::JumpIfFalse, end_label);
         }
 
-        self.evaluate(&expr.right)
+        self.evaluate(&expr.right)?;
+        self.bind_label(end_label);
+        Ok(())
     }
 
     fn visit_assign_expr(&mut self, expr: &Assign) -> Self::Result {
-        let value = self.evaluate(&expr.value)?;
+        self.evaluate(&expr.value)?;
+        self.line = expr.name.line;
 
         if expr.name.is_identifier() {
             let identifier = expr.name.clone();
-            let reg = self.current_register();
-            self.variables.insert(identifier.lexeme.clone(), reg);
-            self.emit_bytecode(ByteCode::SetVariable(identifier.lexeme, reg));
+            match self.resolve_variable(&identifier.lexeme, expr.depth.get()) {
+                VariableKind::Local(local) => self.emit_bytecode(ByteCode::Star(local)),
+                VariableKind::Upvalue(index) => self.emit_bytecode(ByteCode::StaUpvalue(index)),
+                VariableKind::Global => {
+                    self.emit_bytecode(ByteCode::StaGlobal(identifier.lexeme));
+                }
+            }
         }
-        self.values
-            .insert(expr.name.lexeme.to_string(), value.clone().unwrap());
-        Ok(value)
+        Ok(())
     }
 
     fn visit_variable_expr(&mut self, expr: &Variable) -> Self::Result {
-        let identifier = expr.name.clone();
-        let register = self.variables.get(&identifier.lexeme).unwrap();
-        self.emit_bytecode(ByteCode::GetVariable(identifier.lexeme.clone(), *register));
-        let value = self.values.get(&identifier.lexeme).cloned();
-        Ok(value)
+        self.line = expr.name.line;
+        self.load_variable(&expr.name.lexeme, expr.depth.get());
+        Ok(())
     }
 
-    fn visit_literal_expr(&mut self, expr: &LiteralExp) -> Self::Result {
-        let dest = self.allocate_register();
-        let value = expr.value.to_owned();
+    fn visit_get_expr(&mut self, expr: &Get) -> Self::Result {
+        self.evaluate(&expr.object)?;
+        self.line = expr.name.line;
+        self.emit_bytecode(ByteCode::GetProperty(expr.name.lexeme.clone()));
+        Ok(())
+    }
 
-        match value.clone() {
-            Some(Value::Number(_)) => {
-                self.emit_bytecode(ByteCode::Load(dest.into(), value.clone().unwrap()))
-            }
-            Some(Value::String(string)) => {
-                self.emit_bytecode(ByteCode::NewString(dest.into(), string))
-            }
-            _ => {}
+    fn visit_set_expr(&mut self, expr: &Set) -> Self::Result {
+        let mark = self.register_count as i32;
+        let object = self.evaluate_to_register(&expr.object, &[&expr.value])?;
+        self.evaluate(&expr.value)?;
+        self.line = expr.name.line;
+        self.emit_bytecode(ByteCode::SetProperty(object, expr.name.lexeme.clone()));
+        self.release_registers(mark);
+        Ok(())
+    }
+
+    fn visit_this_expr(&mut self, expr: &This) -> Self::Result {
+        self.line = expr.keyword.line;
+        self.load_variable("this", expr.depth.get());
+        Ok(())
+    }
+
+    fn visit_super_expr(&mut self, expr: &Super) -> Self::Result {
+        // The superclass is found through the method's home class at
+        // runtime, so only the receiver has to be loaded.
+        self.line = expr.keyword.line;
+        self.load_variable("this", expr.depth.get());
+        self.emit_bytecode(ByteCode::GetSuper(expr.method.lexeme.clone()));
+        Ok(())
+    }
+
+    fn visit_call_expr(&mut self, expr: &Call) -> Self::Result {
+        let mark = self.register_count as i32;
+        let operands: Vec<&Expr> = expr.arguments.iter().collect();
+        let callee = self.evaluate_to_register(&expr.callee, &operands)?;
+
+        let mut arguments = vec![];
+        for (index, argument) in operands.iter().enumerate() {
+            arguments.push(self.evaluate_to_register(argument, &operands[index + 1..])?);
         }
 
-        Ok(value)
+        self.line = expr.paren.line;
+        self.emit_bytecode(ByteCode::Call(callee, arguments));
+        self.release_registers(mark);
+        Ok(())
+    }
+
+    fn visit_literal_expr(&mut self, expr: &LiteralExp) -> Self::Result {
+        self.line = expr.line;
+        match expr.value.to_owned() {
+            Some(Value::Nil) => self.emit_bytecode(ByteCode::LdaNil),
+            Some(value) => self.emit_bytecode(ByteCode::LdaConstant(value)),
+            None => {}
+        }
+        Ok(())
     }
 
     fn visit_grouping_expr(&mut self, expr: &Grouping) -> Self::Result {
@@ -177,89 +639,62 @@ impl VisitorExpr for ByteCodeGenerator {
     }
 
     fn visit_unary_expr(&mut self, expr: &Unary) -> Self::Result {
-        let right = self.evaluate(&expr.right)?.unwrap();
+        self.evaluate(&expr.right)?;
+        self.line = expr.operator.line;
 
         match expr.operator.type_ {
-            Bang => Ok(Some(Value::Boolean(!is_truthy(&right)))),
-            Minus => match right {
-                Value::Number(n) => {
-                    check_number_and_operand(&expr.operator, &right)?;
-                    Ok(Some(Value::Number(-n)))
-                }
-                _ => Ok(Some(Value::Nil)),
-            },
-            _ => Ok(None),
+            Bang => self.emit_bytecode(ByteCode::LogicalNot),
+            Minus => self.emit_bytecode(ByteCode::Negate),
+            _ => {}
         }
+        Ok(())
     }
+
     fn visit_binary_exp(&mut self, expr: &Binary) -> Self::Result {
-        let left = self.evaluate(&expr.left)?.unwrap();
-        let mut src1 = self.current_register();
-        if let Expr::Variable(variable) = expr.left.as_ref() {
-            src1 = self
-                .variables
-                .get(&variable.name.lexeme)
-                .unwrap()
-                .to_owned();
-        }
-        let right = self.evaluate(&expr.right)?.unwrap();
-        let mut src2 = self.current_register();
-        let dest = self.allocate_register();
-        if let Expr::Variable(variable) = expr.right.as_ref() {
-            src2 = self
-                .variables
-                .get(&variable.name.lexeme)
-                .unwrap()
-                .to_owned();
-        }
+        let mark = self.register_count as i32;
+        let src = self.evaluate_to_register(&expr.left, &[&expr.right])?;
+        self.evaluate(&expr.right)?;
+        self.release_registers(mark);
+        self.line = expr.operator.line;
 
         match expr.operator.type_ {
-            Minus => {
-                self.emit_bytecode(ByteCode::Sub(dest, src1, src2));
-                check_number_operands(&expr.operator, &left, &right)?;
-                Ok(Some(left - right))
-            }
-            Slash => {
-                self.emit_bytecode(ByteCode::Div(dest, src1, src2));
-                check_number_operands(&expr.operator, &left, &right)?;
-                Ok(Some(left / right))
-            }
-            Star => {
-                self.emit_bytecode(ByteCode::Mul(dest, src1, src2));
-                check_number_operands(&expr.operator, &left, &right)?;
-                Ok(Some(left * right))
-            }
-            Plus => {
-                self.emit_bytecode(ByteCode::Add(dest, src1, src2));
-                check_number_operands(&expr.operator, &left, &right)?;
-                Ok(Some(left + right))
-            }
-            Greater => {
-                self.emit_bytecode(ByteCode::TestGreaterThan(src1, src2));
-                check_number_operands(&expr.operator, &left, &right)?;
-                Ok(Some(Value::Boolean(left > right)))
-            }
-            GreaterEqual => {
-                self.emit_bytecode(ByteCode::TestGreaterEqThan(src1, src2));
-                check_number_operands(&expr.operator, &left, &right)?;
-                Ok(Some(Value::Boolean(left >= right)))
-            }
-            Less => {
-                self.emit_bytecode(ByteCode::TestLessThan(src1, src2));
-                check_number_operands(&expr.operator, &left, &right)?;
-                Ok(Some(Value::Boolean(left < right)))
-            }
-            LessEqual => {
-                self.emit_bytecode(ByteCode::TestLessEqThan(src1, src2));
-                check_number_operands(&expr.operator, &left, &right)?;
-                Ok(Some(Value::Boolean(left <= right)))
-            }
-            BangEqual => Ok(Some(Value::Boolean(!is_equal(&Some(left), &Some(right))))),
-            EqualEqual => Ok(Some(Value::Boolean(is_equal(&Some(left), &Some(right))))),
-            _ => Err(LoxErrors::RunTimeException(Error::new(
-                expr.operator.line,
-                "Operands must be two numbers or two string".to_string(),
-            ))),
+            Minus => self.emit_bytecode(ByteCode::Sub(src)),
+            Slash => self.emit_bytecode(ByteCode::Div(src)),
+            Star => self.emit_bytecode(ByteCode::Mul(src)),
+            Plus => self.emit_bytecode(ByteCode::Add(src)),
+            Greater => self.emit_bytecode(ByteCode::TestGreaterThan(src)),
+            GreaterEqual => self.emit_bytecode(ByteCode::TestGreaterEqThan(src)),
+            Less => self.emit_bytecode(ByteCode::TestLessThan(src)),
+            LessEqual => self.emit_bytecode(ByteCode::TestLessEqThan(src)),
+            EqualEqual => self.emit_bytecode(ByteCode::Equal(src)),
+            BangEqual => self.emit_bytecode(ByteCode::NotEqual(src)),
+            _ => {}
         }
+
+        Ok(())
+    }
+}
+
+/// Whether evaluating `expr` may change the variable `operand` reads. Calls
+/// may run a closure that assigns it, so any call counts.
+fn may_assign(expr: &Expr, operand: &Expr) -> bool {
+    let Expr::Variable(variable) = operand else {
+        // `this` can't be assigned.
+        return false;
+    };
+    let assigns = |expr: &Expr| may_assign(expr, operand);
+    match expr {
+        Expr::Assign(assign) => {
+            assign.name.lexeme == variable.name.lexeme || assigns(&assign.value)
+        }
+        Expr::Call(_) => true,
+        Expr::Binary(binary) => assigns(&binary.left) || assigns(&binary.right),
+        Expr::Logical(logical) => assigns(&logical.left) || assigns(&logical.right),
+        Expr::Grouping(grouping) => assigns(&grouping.expression),
+        Expr::Unary(unary) => assigns(&unary.right),
+        Expr::Get(get) => assigns(&get.object),
+        Expr::Set(set) => assigns(&set.object) || assigns(&set.value),
+        Expr::Literal(_) | Expr::Variable(_) | Expr::This(_) | Expr::Super(_) => false,
     }
 }
 
@@ -274,11 +709,20 @@ impl VisitorExpr for ByteCodeGenerator {
 impl VisitorStmt for ByteCodeGenerator {
     type Result = LoxResult<()>;
     fn visit_if_stmt(&mut self, stmt: &IfStmt) -> Self::Result {
-        let value = self.evaluate(&stmt.condition)?.unwrap();
-        if is_truthy(&value) {
-            self.execute(&stmt.then_branch)?;
-        } else if let Some(else_branch) = &stmt.else_branch {
+        self.emit_condition(&stmt.condition)?;
+        let else_label = self.allocate_label();
+        self.emit_jump(ByteCode::JumpIfFalse, else_label);
+
+        self.execute(&stmt.then_branch)?;
+
+        if let Some(else_branch) = &stmt.else_branch {
+            let end_label = self.allocate_label();
+            self.emit_jump(ByteCode::Jump, end_label);
+            self.bind_label(else_label);
             self.execute(else_branch.as_ref())?;
+            self.bind_label(end_label);
+        } else {
+            self.bind_label(else_label);
         }
 
         Ok(())
@@ -290,105 +734,136 @@ impl VisitorStmt for ByteCodeGenerator {
     }
 
     fn visit_print_stmt(&mut self, stmt: &PrintStmt) -> Self::Result {
-        let expr = stmt.expression.clone();
-        if let Expr::Grouping(expr) = expr {
-            if let Expr::Variable(variable) = *expr.expression.clone() {
-                let register = self.variables.get(&variable.name.lexeme).unwrap();
-                self.emit_bytecode(ByteCode::Print(register.clone()));
-            }
-        }
+        self.evaluate(&stmt.expression)?;
+        self.emit_bytecode(ByteCode::Print);
         Ok(())
     }
 
     fn visit_var_stmt(&mut self, stmt: &VarStmt) -> Self::Result {
-        let mut value = None;
-        if let Some(initializer) = &stmt.initializer {
-            value = self.evaluate(initializer)?;
-        }
         let identifier = stmt.name.lexeme.clone();
+        self.line = stmt.name.line;
+
+        // Copying one local into another doesn't need the accumulator.
+        let copied = match &stmt.initializer {
+            Some(initializer) if self.scope_depth > 0 => self.local_operand(initializer),
+            _ => None,
+        };
+        if copied.is_none() {
+            match &stmt.initializer {
+                Some(initializer) => self.evaluate(initializer)?,
+                None => self.emit_bytecode(ByteCode::LdaNil),
+            }
+        }
 
-        self.variables
-            .insert(identifier.clone(), self.current_register());
-
-        if let Some(value) = value {
-            self.values.insert(identifier, value);
+        self.line = stmt.name.line;
+        if self.scope_depth > 0 {
+            // The initializer's temporaries are dead, so the local can take
+            // the first of them as its home register.
+            self.release_registers(self.local_count());
+            let home = self.declare_local(&identifier);
+            match copied {
+                Some(register) => self.emit_bytecode(ByteCode::Move(home, register)),
+                None => self.emit_bytecode(ByteCode::Star(home)),
+            }
         } else {
-            let reg = self.allocate_register();
-            self.emit_bytecode(ByteCode::LoadUndefined(reg));
-            self.values.insert(identifier, Value::Undefined);
+            self.emit_bytecode(ByteCode::DefineGlobal(identifier));
         }
-        self.emit_bytecode(ByteCode::SetVariable(
-            stmt.name.lexeme.clone(),
-            self.current_register(),
-        ));
         Ok(())
     }
 
     fn visit_block_stmt(&mut self, stmt: &BlockStmt) -> Self::Result {
-        self.execute_block(&stmt.statements)?;
-        Ok(())
+        self.scope_depth += 1;
+        let result = self.execute_block(&stmt.statements);
+        self.end_scope();
+        result
     }
 
     fn visit_while_stmt(&mut self, stmt: &WhileStmt) -> Self::Result {
-        let label = self.allocate_label();
-
-        let body = *stmt.body.clone();
-        self.emit_bytecode(ByteCode::Label(label.clone()));
-
-        match body {
-            Stmt::BlockStmt(block) => block.accept(self)?,
-            _ => {
-                self.execute(&body)?;
-            }
-        }
-        stmt.condition.accept(self)?;
-        self.emit_bytecode(ByteCode::JumpIfTrue(label));
+        let start_label = self.allocate_label();
+        let exit_label = self.allocate_label();
 
         /*
-        LOAD R1, 5
-        LOAD R2, 10
-        {label}:
-            PRINT R1
-            INCR R1, 1
-            TestLessThan R1, R2
-            JmpIfTrue {label}
+        {start}:
+            Ldar R2
+            TestLessThan R1
+            JumpIfFalse {exit}
+            Ldar R1
+            Print
+            Jump {start}
+        {exit}:
         */
+        self.bind_label(start_label);
+        self.emit_condition(&stmt.condition)?;
+        self.emit_jump(ByteCode::JumpIfFalse, exit_label);
+        self.execute(&stmt.body)?;
+        self.emit_jump(ByteCode::Jump, start_label);
+        self.bind_label(exit_label);
         Ok(())
     }
-}
 
-fn is_truthy(object: &Value) -> bool {
-    match *object {
-        Value::Nil => false,
-        Value::Boolean(b) => b,
-        _ => false,
+    fn visit_function_stmt(&mut self, stmt: &FunctionStmt) -> Self::Result {
+        let identifier = stmt.name.lexeme.clone();
+        self.line = stmt.name.line;
+
+        if self.scope_depth > 0 {
+            // Declare the local before compiling the body so that the
+            // function can capture itself and recurse.
+            let register = self.declare_local(&identifier);
+            let function = self.compile_function(stmt, FunctionKind::Function)?;
+            self.emit_bytecode(ByteCode::Closure(Rc::new(function)));
+            self.emit_bytecode(ByteCode::Star(register));
+        } else {
+            let function = self.compile_function(stmt, FunctionKind::Function)?;
+            self.emit_bytecode(ByteCode::Closure(Rc::new(function)));
+            self.emit_bytecode(ByteCode::DefineGlobal(identifier));
+        }
+        Ok(())
     }
-}
 
-fn is_equal(a: &Literal, b: &Literal) -> bool {
-    if a.is_none() && b.is_none() {
-        false
-    } else {
-        a.eq(b)
+    fn visit_return_stmt(&mut self, stmt: &ReturnStmt) -> Self::Result {
+        self.line = stmt.keyword.line;
+        match &stmt.value {
+            Some(value) => {
+                self.evaluate(value)?;
+                self.line = stmt.keyword.line;
+                self.emit_bytecode(ByteCode::Return);
+            }
+            None => self.emit_return(),
+        }
+        Ok(())
     }
-}
 
-fn check_number_and_operand(operator: &Token, operand: &Value) -> LoxResult<()> {
-    match operand {
-        Value::Number(_) => Ok(()),
-        _ => Err(LoxErrors::RunTimeException(Error::new(
-            operator.line,
-            "Operand must be a number".to_string(),
-        ))),
-    }
-}
+    fn visit_class_stmt(&mut self, stmt: &ClassStmt) -> Self::Result {
+        let identifier = stmt.name.lexeme.clone();
+        self.line = stmt.name.line;
 
-fn check_number_operands(operator: &Token, left: &Value, right: &Value) -> LoxResult<()> {
-    match (left, right) {
-        (Value::Number(_), Value::Number(_)) => Ok(()),
-        _ => Err(LoxErrors::RunTimeException(Error::new(
-            operator.line,
-            "Operands must be numbers".to_string(),
-        ))),
+        // Like functions, the class is declared before its methods are
+        // generated so that they can refer to it by name.
+        let class = if self.scope_depth > 0 {
+            self.declare_local(&identifier)
+        } else {
+            self.allocate_register()
+        };
+        let superclass = stmt
+            .superclass
+            .as_ref()
+            .map(|variable| self.variable_register(&variable.name.lexeme, variable.depth.get()));
+        self.emit_bytecode(ByteCode::Class(identifier.clone(), superclass));
+        self.emit_bytecode(ByteCode::Star(class));
+        if self.scope_depth == 0 {
+            self.emit_bytecode(ByteCode::DefineGlobal(identifier));
+        }
+
+        for method in stmt.methods.iter() {
+            let kind = if method.name.lexeme == "init" {
+                FunctionKind::Initializer
+            } else {
+                FunctionKind::Method
+            };
+            let function = self.compile_function(method, kind)?;
+            self.emit_bytecode(ByteCode::Closure(Rc::new(function)));
+            self.emit_bytecode(ByteCode::Method(class, method.name.lexeme.clone()));
+        }
+        Ok(())
     }
 }
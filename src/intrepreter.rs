@@ -1,41 +1,53 @@
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::rc::Rc;
 
 use crate::ast::{
-    Assign, Binary, BlockStmt, Expr, ExpressionStmt, Grouping, IfStmt, Literal as LiteralExp,
-    Logical, PrintStmt, Stmt, Unary, VarStmt, Variable, VisitorExpr, VisitorStmt, WhileStmt,
+    Assign, Binary, BlockStmt, Call, ClassStmt, Expr, ExpressionStmt, FunctionStmt, Get, Grouping,
+    IfStmt, Literal as LiteralExp, Logical, PrintStmt, ReturnStmt, Set, Stmt, Super, This, Unary,
+    VarStmt, Variable, VisitorExpr, VisitorStmt, WhileStmt,
 };
+use crate::class::{LoxClass, LoxInstance};
 use crate::environment::Environment;
 use crate::error::{Error, LoxErrors, LoxResult};
+use crate::function::LoxFunction;
 use crate::token::Token;
 use crate::token_type::TokenType::{
     self, Bang, BangEqual, EqualEqual, Greater, GreaterEqual, Less, LessEqual, Minus, Plus, Slash,
     Star,
 };
 use crate::tools::*;
-use crate::value::Value;
+use crate::value::{is_equal, is_truthy, Value};
 
 pub struct Intrepreter {
     environment: Rc<RefCell<Environment>>,
+    globals: Rc<RefCell<Environment>>,
     repl: bool,
 }
 
 type Literal = Option<Value>;
 
 impl Intrepreter {
-    fn new(environment: Rc<RefCell<Environment>>, repl: bool) -> Self {
-        Self { environment, repl }
+    fn new(
+        environment: Rc<RefCell<Environment>>,
+        globals: Rc<RefCell<Environment>>,
+        repl: bool,
+    ) -> Self {
+        Self {
+            environment,
+            globals,
+            repl,
+        }
     }
 
     fn evaluate(&mut self, expr: &Expr) -> LoxResult<Literal> {
         expr.accept(self)
     }
 
+    #[cfg(test)]
     pub fn without_repl() -> Self {
-        Self {
-            environment: Rc::new(RefCell::new(Environment::default())),
-            repl: false,
-        }
+        let globals = Rc::new(RefCell::new(Environment::default()));
+        Self::new(globals.clone(), globals, false)
     }
 
     pub fn intrepret(&mut self, statements: &[Stmt]) -> LoxResult<()> {
@@ -55,36 +67,68 @@ impl Intrepreter {
         environment: Rc<RefCell<Environment>>,
         repl: bool,
     ) -> LoxResult<()> {
-        let mut interpreter = Intrepreter::new(environment, repl);
+        let mut interpreter = Intrepreter::new(environment, self.globals.clone(), repl);
         statements
             .iter()
             .try_for_each(|stmt| interpreter.execute(stmt))?;
         Ok(())
     }
-}
 
-impl Default for Intrepreter {
-    fn default() -> Self {
-        Self {
-            environment: Rc::new(RefCell::new(Environment::default())),
-            repl: true,
+    fn call_function(
+        &mut self,
+        function: &LoxFunction,
+        arguments: Vec<Literal>,
+    ) -> LoxResult<Literal> {
+        let mut environment = Environment::new(function.closure.clone());
+        for (param, argument) in function.declaration.params.iter().zip(arguments) {
+            environment.define(param.lexeme.clone(), argument);
+        }
+
+        let result = self.execute_block(
+            &function.declaration.body,
+            Rc::new(RefCell::new(environment)),
+            false,
+        );
+        if function.is_initializer {
+            // `init` always hands back the instance, even on a bare `return;`.
+            result.or_else(|error| match error {
+                LoxErrors::Return(_) => Ok(()),
+                error => Err(error),
+            })?;
+            let this = Token::new(TokenType::This, "this", None, 0);
+            return function.closure.borrow().get(&this);
+        }
+
+        match result {
+            Ok(()) => Ok(Some(Value::Nil)),
+            Err(LoxErrors::Return(value)) => Ok(value),
+            Err(error) => Err(error),
         }
     }
-}
 
-fn is_truthy(object: &Value) -> bool {
-    match *object {
-        Value::Nil => false,
-        Value::Boolean(b) => b,
-        _ => false,
+    /// Reads a variable from the scope the `Resolver` bound it to.
+    fn look_up_variable(&self, name: &Token, depth: Option<usize>) -> LoxResult<Literal> {
+        match depth {
+            Some(depth) => self.environment.borrow().get_at(depth, name),
+            None => self.globals.borrow().get(name),
+        }
+    }
+
+    fn check_arity(&self, paren: &Token, arity: usize, arguments: usize) -> LoxResult<()> {
+        if arguments != arity {
+            return Err(LoxErrors::RunTimeException(Error::new(
+                paren.line,
+                format!("Expected {} arguments but got {}.", arity, arguments),
+            )));
+        }
+        Ok(())
     }
 }
 
-fn is_equal(a: &Literal, b: &Literal) -> bool {
-    if a.is_none() && b.is_none() {
-        false
-    } else {
-        a.eq(b)
+impl Default for Intrepreter {
+    fn default() -> Self {
+        let globals = Rc::new(RefCell::new(Environment::default()));
+        Self::new(globals.clone(), globals, true)
     }
 }
 
@@ -93,7 +137,7 @@ fn check_number_and_operand(operator: &Token, operand: &Value) -> LoxResult<()>
         Value::Number(_) => Ok(()),
         _ => Err(LoxErrors::RunTimeException(Error::new(
             operator.line,
-            "Operand must be a number".to_string(),
+            "Operand must be a number.".to_string(),
         ))),
     }
 }
@@ -103,7 +147,7 @@ fn check_number_operands(operator: &Token, left: &Value, right: &Value) -> LoxRe
         (Value::Number(_), Value::Number(_)) => Ok(()),
         _ => Err(LoxErrors::RunTimeException(Error::new(
             operator.line,
-            "Operands must be numbers".to_string(),
+            "Operands must be numbers.".to_string(),
         ))),
     }
 }
@@ -115,7 +159,11 @@ impl VisitorExpr for Intrepreter {
         let left = self.evaluate(&expr.left)?;
 
         if let Some(left) = left {
-            if expr.operator.type_ == TokenType::Or && is_truthy(&left) {
+            let short_circuits = match expr.operator.type_ {
+                TokenType::Or => is_truthy(&left),
+                _ => !is_truthy(&left),
+            };
+            if short_circuits {
                 return Ok(Some(left));
             }
         }
@@ -125,14 +173,122 @@ impl VisitorExpr for Intrepreter {
 
     fn visit_assign_expr(&mut self, expr: &Assign) -> Self::Result {
         let value = self.evaluate(&expr.value)?;
-        self.environment
-            .borrow_mut()
-            .assign(expr.name.clone(), value.clone())?;
+        match expr.depth.get() {
+            Some(depth) => {
+                self.environment
+                    .borrow_mut()
+                    .assign_at(depth, expr.name.clone(), value.clone())
+            }
+            None => self
+                .globals
+                .borrow_mut()
+                .assign(expr.name.clone(), value.clone())?,
+        }
         Ok(value)
     }
 
     fn visit_variable_expr(&mut self, expr: &Variable) -> Self::Result {
-        self.environment.borrow_mut().get(expr.name.clone())
+        self.look_up_variable(&expr.name, expr.depth.get())
+    }
+
+    fn visit_call_expr(&mut self, expr: &Call) -> Self::Result {
+        let callee = self.evaluate(&expr.callee)?;
+
+        let mut arguments = vec![];
+        for argument in expr.arguments.iter() {
+            arguments.push(self.evaluate(argument)?);
+        }
+
+        match callee {
+            Some(Value::Function(function)) => {
+                self.check_arity(&expr.paren, function.arity(), arguments.len())?;
+                self.call_function(&function, arguments)
+            }
+            Some(Value::Class(class)) => {
+                let instance =
+                    Value::Instance(Rc::new(RefCell::new(LoxInstance::new(class.clone()))));
+                match class.find_method("init") {
+                    Some(Value::Function(initializer)) => {
+                        self.check_arity(&expr.paren, initializer.arity(), arguments.len())?;
+                        let initializer = initializer.bind(instance.clone());
+                        self.call_function(&initializer, arguments)?;
+                    }
+                    _ => self.check_arity(&expr.paren, 0, arguments.len())?,
+                }
+                Ok(Some(instance))
+            }
+            _ => Err(LoxErrors::RunTimeException(Error::new(
+                expr.paren.line,
+                "Can only call functions and classes.".to_string(),
+            ))),
+        }
+    }
+
+    fn visit_get_expr(&mut self, expr: &Get) -> Self::Result {
+        let object = self.evaluate(&expr.object)?;
+        let Some(Value::Instance(instance)) = object else {
+            return Err(LoxErrors::RunTimeException(Error::new(
+                expr.name.line,
+                "Only instances have properties.".to_string(),
+            )));
+        };
+
+        if let Some(value) = instance.borrow().fields.get(&expr.name.lexeme) {
+            return Ok(Some(value.clone()));
+        }
+        let method = instance.borrow().class.find_method(&expr.name.lexeme);
+        match method {
+            Some(Value::Function(method)) => Ok(Some(Value::Function(Rc::new(
+                method.bind(Value::Instance(instance.clone())),
+            )))),
+            _ => Err(LoxErrors::RunTimeException(Error::new(
+                expr.name.line,
+                format!("Undefined property '{}'.", expr.name.lexeme),
+            ))),
+        }
+    }
+
+    fn visit_set_expr(&mut self, expr: &Set) -> Self::Result {
+        let object = self.evaluate(&expr.object)?;
+        let Some(Value::Instance(instance)) = object else {
+            return Err(LoxErrors::RunTimeException(Error::new(
+                expr.name.line,
+                "Only instances have fields.".to_string(),
+            )));
+        };
+
+        let value = self.evaluate(&expr.value)?;
+        instance.borrow_mut().fields.insert(
+            expr.name.lexeme.clone(),
+            value.clone().unwrap_or(Value::Nil),
+        );
+        Ok(value)
+    }
+
+    fn visit_this_expr(&mut self, expr: &This) -> Self::Result {
+        self.look_up_variable(&expr.keyword, expr.depth.get())
+    }
+
+    fn visit_super_expr(&mut self, expr: &Super) -> Self::Result {
+        let depth = expr.depth.get().expect("'super' is always a local");
+        let superclass = self.environment.borrow().get_at(depth, &expr.keyword)?;
+        // The method's `this` scope sits right inside the one binding `super`.
+        let this = Token::new(TokenType::This, "this", None, expr.keyword.line);
+        let object = self.environment.borrow().get_at(depth - 1, &this)?;
+
+        let method = match superclass {
+            Some(Value::Class(superclass)) => superclass.find_method(&expr.method.lexeme),
+            _ => None,
+        };
+        match (method, object) {
+            (Some(Value::Function(method)), Some(object)) => {
+                Ok(Some(Value::Function(Rc::new(method.bind(object)))))
+            }
+            _ => Err(LoxErrors::RunTimeException(Error::new(
+                expr.method.line,
+                format!("Undefined property '{}'.", expr.method.lexeme),
+            ))),
+        }
     }
 
     fn visit_literal_expr(&mut self, expr: &LiteralExp) -> Self::Result {
@@ -148,13 +304,10 @@ impl VisitorExpr for Intrepreter {
 
         match expr.operator.type_ {
             Bang => Ok(Some(Value::Boolean(!is_truthy(&right)))),
-            Minus => match right {
-                Value::Number(n) => {
-                    check_number_and_operand(&expr.operator, &right)?;
-                    Ok(Some(Value::Number(-n)))
-                }
-                _ => Ok(Some(Value::Nil)),
-            },
+            Minus => {
+                check_number_and_operand(&expr.operator, &right)?;
+                Ok(Some(-right))
+            }
             _ => Ok(None),
         }
     }
@@ -175,7 +328,13 @@ impl VisitorExpr for Intrepreter {
                 check_number_operands(&expr.operator, &left, &right)?;
                 Ok(Some(left * right))
             }
-            Plus => Ok(Some(left + right)),
+            Plus => match left + right {
+                Value::ArithmeticError => Err(LoxErrors::RunTimeException(Error::new(
+                    expr.operator.line,
+                    "Operands must be two numbers or two strings.".to_string(),
+                ))),
+                sum => Ok(Some(sum)),
+            },
             Greater => {
                 check_number_operands(&expr.operator, &left, &right)?;
                 Ok(Some(Value::Boolean(left > right)))
@@ -192,11 +351,11 @@ impl VisitorExpr for Intrepreter {
                 check_number_operands(&expr.operator, &left, &right)?;
                 Ok(Some(Value::Boolean(left <= right)))
             }
-            BangEqual => Ok(Some(Value::Boolean(!is_equal(&Some(left), &Some(right))))),
-            EqualEqual => Ok(Some(Value::Boolean(is_equal(&Some(left), &Some(right))))),
+            BangEqual => Ok(Some(Value::Boolean(!is_equal(&left, &right)))),
+            EqualEqual => Ok(Some(Value::Boolean(is_equal(&left, &right)))),
             _ => Err(LoxErrors::RunTimeException(Error::new(
                 expr.operator.line,
-                "Operands must be two numbers or two string".to_string(),
+                "Operands must be two numbers or two strings.".to_string(),
             ))),
         }
     }
@@ -225,7 +384,7 @@ impl VisitorStmt for Intrepreter {
 
     fn visit_expression_stmt(&mut self, stmt: &ExpressionStmt) -> Self::Result {
         if self.repl {
-            if let Some(value) = self.evaluate(&stmt.expression)? {}
+            if let Some(_value) = self.evaluate(&stmt.expression)? {}
         } else {
             self.evaluate(&stmt.expression)?;
         }
@@ -239,7 +398,8 @@ impl VisitorStmt for Intrepreter {
     }
 
     fn visit_var_stmt(&mut self, stmt: &VarStmt) -> Self::Result {
-        let mut value = None;
+        // Variables declared without an initializer start out as nil.
+        let mut value = Some(Value::Nil);
 
         if let Some(initializer) = &stmt.initializer {
             value = self.evaluate(initializer)?;
@@ -252,7 +412,12 @@ impl VisitorStmt for Intrepreter {
     }
 
     fn visit_block_stmt(&mut self, stmt: &BlockStmt) -> Self::Result {
-        self.execute_block(&stmt.statements, self.environment.clone(), self.repl)?;
+        let environment = Environment::new(self.environment.clone());
+        self.execute_block(
+            &stmt.statements,
+            Rc::new(RefCell::new(environment)),
+            self.repl,
+        )?;
         Ok(())
     }
 
@@ -262,15 +427,100 @@ impl VisitorStmt for Intrepreter {
         }
         Ok(())
     }
+
+    fn visit_function_stmt(&mut self, stmt: &FunctionStmt) -> Self::Result {
+        let function = LoxFunction::new(stmt.clone(), self.environment.clone(), false);
+        self.environment.borrow_mut().define(
+            stmt.name.lexeme.clone(),
+            Some(Value::Function(Rc::new(function))),
+        );
+        Ok(())
+    }
+
+    fn visit_class_stmt(&mut self, stmt: &ClassStmt) -> Self::Result {
+        let mut superclass = None;
+        if let Some(variable) = &stmt.superclass {
+            match self.visit_variable_expr(variable)? {
+                Some(Value::Class(class)) => superclass = Some(class),
+                _ => {
+                    return Err(LoxErrors::RunTimeException(Error::new(
+                        variable.name.line,
+                        "Superclass must be a class.".to_string(),
+                    )))
+                }
+            }
+        }
+
+        self.environment
+            .borrow_mut()
+            .define(stmt.name.lexeme.clone(), None);
+
+        // Methods of a subclass close over an extra scope that binds `super`.
+        let mut closure = self.environment.clone();
+        if let Some(superclass) = &superclass {
+            let mut environment = Environment::new(closure);
+            environment.define("super".to_string(), Some(Value::Class(superclass.clone())));
+            closure = Rc::new(RefCell::new(environment));
+        }
+
+        let methods: HashMap<String, Value> = stmt
+            .methods
+            .iter()
+            .map(|method| {
+                let function = LoxFunction::new(
+                    method.clone(),
+                    closure.clone(),
+                    method.name.lexeme == "init",
+                );
+                (
+                    method.name.lexeme.clone(),
+                    Value::Function(Rc::new(function)),
+                )
+            })
+            .collect();
+
+        let class = LoxClass::new(stmt.name.lexeme.clone(), superclass);
+        class.methods.replace(methods);
+        self.environment
+            .borrow_mut()
+            .assign(stmt.name.clone(), Some(Value::Class(Rc::new(class))))
+    }
+
+    fn visit_return_stmt(&mut self, stmt: &ReturnStmt) -> Self::Result {
+        let mut value = Some(Value::Nil);
+        if let Some(expr) = &stmt.value {
+            value = self.evaluate(expr)?;
+        }
+
+        Err(LoxErrors::Return(value))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::parser::Parser;
+    use crate::resolver::Resolver;
+    use crate::scanner::Scanner;
+
+    fn run_source(source: &str) -> Intrepreter {
+        let tokens = Scanner::new(source.to_string()).scan_tokens();
+        let statements = Parser::new(&tokens).parse().unwrap();
+        Resolver::default().resolve(&statements).unwrap();
+        let mut terp = Intrepreter::without_repl();
+        terp.intrepret(&statements).unwrap();
+        terp
+    }
+
+    fn global(terp: &Intrepreter, name: &str) -> Literal {
+        let token = Token::new(TokenType::Identifier, name, None, 1);
+        terp.environment.borrow().get(&token).unwrap()
+    }
 
     fn make_literal_num(num: f64) -> Box<Expr> {
         Box::new(Expr::Literal(LiteralExp {
             value: Some(Value::Number(num)),
+            line: 1,
         }))
     }
 
@@ -294,6 +544,7 @@ mod tests {
             operator: Token::new(Bang, "!", None, 1),
             right: Box::new(Expr::Literal(LiteralExp {
                 value: Some(Value::Boolean(true)),
+                line: 1,
             })),
         };
         let result = terp.visit_unary_expr(&unary);
@@ -307,10 +558,12 @@ mod tests {
         let binary_expr = Binary {
             left: Box::new(Expr::Literal(LiteralExp {
                 value: Some(Value::Number(100.0)),
+                line: 1,
             })),
             operator: Token::new(Minus, "-", None, 1),
             right: Box::new(Expr::Literal(LiteralExp {
                 value: Some(Value::Number(50.0)),
+                line: 1,
             })),
         };
 
@@ -396,4 +649,207 @@ mod tests {
         assert!(result.is_ok());
         assert_eq!(result.ok(), Some(Some(Value::Boolean(true))));
     }
+
+    #[test]
+    fn test_logical_operators_short_circuit() {
+        let terp = run_source(
+            "var and_false = false and undefined;
+             var and_number = 1 and 2;
+             var and_true = true and \"right\";
+             var or_true = true or undefined;
+             var or_false = false or \"right\";
+             var or_zero = 0 or 5;
+             var branch = 0;
+             if (\"\") branch = 1; else branch = 2;",
+        );
+        assert_eq!(global(&terp, "and_false"), Some(Value::Boolean(false)));
+        assert_eq!(global(&terp, "and_number"), Some(Value::Number(2.0)));
+        assert_eq!(
+            global(&terp, "and_true"),
+            Some(Value::String("right".to_string()))
+        );
+        assert_eq!(global(&terp, "or_true"), Some(Value::Boolean(true)));
+        assert_eq!(
+            global(&terp, "or_false"),
+            Some(Value::String("right".to_string()))
+        );
+        assert_eq!(global(&terp, "or_zero"), Some(Value::Number(0.0)));
+        assert_eq!(global(&terp, "branch"), Some(Value::Number(1.0)));
+    }
+
+    #[test]
+    fn test_uninitialized_variables_are_nil() {
+        let terp = run_source("var r; print r; var copy = r;");
+        assert_eq!(global(&terp, "copy"), Some(Value::Nil));
+    }
+
+    #[test]
+    fn test_operand_type_errors_match_the_vm() {
+        let error = |source: &str| {
+            let tokens = Scanner::new(source.to_string()).scan_tokens();
+            let statements = Parser::new(&tokens).parse().unwrap();
+            Resolver::default().resolve(&statements).unwrap();
+            let error = Intrepreter::without_repl()
+                .intrepret(&statements)
+                .unwrap_err();
+            assert!(matches!(error, LoxErrors::RunTimeException(_)));
+            error.to_string()
+        };
+        assert_eq!(
+            error("print -\"a\";"),
+            "Error occured at line 1: Operand must be a number."
+        );
+        assert_eq!(
+            error("print 1 + true;"),
+            "Error occured at line 1: Operands must be two numbers or two strings."
+        );
+        assert_eq!(
+            error("print 1 < nil;"),
+            "Error occured at line 1: Operands must be numbers."
+        );
+    }
+
+    #[test]
+    fn test_recursive_function() {
+        let terp = run_source(
+            "fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+             var result = fib(10);",
+        );
+        assert_eq!(global(&terp, "result"), Some(Value::Number(55.0)));
+    }
+
+    #[test]
+    fn test_function_without_return_yields_nil() {
+        let terp = run_source("fun noop() {} var result = noop();");
+        assert_eq!(global(&terp, "result"), Some(Value::Nil));
+    }
+
+    #[test]
+    fn test_closure_outlives_enclosing_call() {
+        let terp = run_source(
+            "fun makeCounter() {
+               var count = 0;
+               fun increment() { count = count + 1; return count; }
+               return increment;
+             }
+             var first = makeCounter();
+             var second = makeCounter();
+             first();
+             first();
+             var a = first();
+             var b = second();",
+        );
+        assert_eq!(global(&terp, "a"), Some(Value::Number(3.0)));
+        assert_eq!(global(&terp, "b"), Some(Value::Number(1.0)));
+    }
+
+    #[test]
+    fn test_classes_methods_and_super() {
+        let terp = run_source(
+            "class Animal {
+               init(legs) { this.legs = legs; }
+               describe() { return this.legs; }
+             }
+             class Bird < Animal {
+               init() { super.init(2); this.wings = 2; }
+               describe() {
+                 fun total() { return super.describe() + this.wings; }
+                 return total;
+               }
+             }
+             var bird = Bird();
+             var legs = bird.legs;
+             var total = bird.describe();
+             var limbs = total();
+             bird.legs = 1;
+             var after = bird.init().describe()();",
+        );
+        assert_eq!(global(&terp, "legs"), Some(Value::Number(2.0)));
+        assert_eq!(global(&terp, "limbs"), Some(Value::Number(4.0)));
+        assert_eq!(global(&terp, "after"), Some(Value::Number(4.0)));
+    }
+
+    #[test]
+    fn test_loops_test_condition_before_body() {
+        let terp = run_source(
+            "var skipped = 0;
+             for (var i = 10; i < 3; i = i + 1) { skipped = skipped + 1; }
+             var sum = 0;
+             var i = 0;
+             while (i < 5) { i = i + 1; sum = sum + i; }
+             fun count(n) {
+               var total = 0;
+               for (var j = 0; j < n; j = j + 1) total = total + 2;
+               return total;
+             }
+             var none = count(0);
+             var some = count(4);",
+        );
+        assert_eq!(global(&terp, "skipped"), Some(Value::Number(0.0)));
+        assert_eq!(global(&terp, "sum"), Some(Value::Number(15.0)));
+        assert_eq!(global(&terp, "none"), Some(Value::Number(0.0)));
+        assert_eq!(global(&terp, "some"), Some(Value::Number(8.0)));
+    }
+
+    #[test]
+    fn test_blocks_shadow_and_restore_variables() {
+        let terp = run_source(
+            "var a = \"global a\";
+             var b = \"global b\";
+             var c = \"global c\";
+             var inner;
+             var outer;
+             {
+               var a = \"outer a\";
+               var b = \"outer b\";
+               {
+                 var a = \"inner a\";
+                 inner = a + b + c;
+               }
+               outer = a + b + c;
+             }
+             var global = a + b + c;",
+        );
+        assert_eq!(
+            global(&terp, "inner"),
+            Some(Value::String("inner aouter bglobal c".to_string()))
+        );
+        assert_eq!(
+            global(&terp, "outer"),
+            Some(Value::String("outer aouter bglobal c".to_string()))
+        );
+        assert_eq!(
+            global(&terp, "global"),
+            Some(Value::String("global aglobal bglobal c".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_block_locals_vanish_at_block_exit() {
+        let tokens = Scanner::new("{ var hidden = 1; } print hidden;".to_string()).scan_tokens();
+        let statements = Parser::new(&tokens).parse().unwrap();
+        Resolver::default().resolve(&statements).unwrap();
+        let error = Intrepreter::without_repl()
+            .intrepret(&statements)
+            .unwrap_err();
+        assert!(error.to_string().ends_with("Undefined variable 'hidden'."));
+    }
+
+    #[test]
+    fn test_closures_keep_the_binding_they_resolved() {
+        let terp = run_source(
+            "var a = \"global\";
+             var seen = \"\";
+             {
+               fun show() { seen = seen + a; }
+               show();
+               var a = \"block\";
+               show();
+             }",
+        );
+        assert_eq!(
+            global(&terp, "seen"),
+            Some(Value::String("globalglobal".to_string()))
+        );
+    }
 }
@@ -0,0 +1,48 @@
+/// Maps instruction offsets back to source lines.
+///
+/// Consecutive instructions usually come from the same line, so the table
+/// only stores the offset at which each run of instructions starts along
+/// with the line shared by that run.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LineTable {
+    /// `(first offset, line)` of each run, in increasing offset order.
+    runs: Vec<(usize, usize)>,
+}
+
+impl LineTable {
+    /// Records that the instruction at `offset`, which comes after every
+    /// offset recorded so far, was generated from `line`.
+    pub fn push(&mut self, offset: usize, line: usize) {
+        if self.runs.last().map(|&(_, last)| last) != Some(line) {
+            self.runs.push((offset, line));
+        }
+    }
+
+    /// Returns the source line of the instruction at `offset`.
+    pub fn line(&self, offset: usize) -> usize {
+        let run = self.runs.partition_point(|&(start, _)| start <= offset);
+        self.runs[run - 1].1
+    }
+
+    /// The `(first offset, line)` pairs the table is made of.
+    pub fn runs(&self) -> &[(usize, usize)] {
+        &self.runs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_runs_of_the_same_line_are_stored_once() {
+        let mut table = LineTable::default();
+        for (offset, line) in [1, 1, 1, 2, 2, 4, 1].into_iter().enumerate() {
+            table.push(offset, line);
+        }
+        assert_eq!(table.runs, vec![(0, 1), (3, 2), (5, 4), (6, 1)]);
+
+        let lines: Vec<_> = (0..7).map(|offset| table.line(offset)).collect();
+        assert_eq!(lines, vec![1, 1, 1, 2, 2, 4, 1]);
+    }
+}
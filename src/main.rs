@@ -1,14 +1,25 @@
 use std::{
     fs,
     io::{self, stdout, Write},
+    path::Path,
 };
 
+mod assembler;
 mod ast;
+mod bytecode_file;
+mod cache;
+mod class;
+mod constant_pool;
+mod disassembler;
+mod encoding;
 mod environment;
 mod error;
+mod function;
 mod generator;
 mod intrepreter;
+mod line_table;
 mod parser;
+mod resolver;
 mod scanner;
 mod token;
 mod token_type;
@@ -17,9 +28,13 @@ mod value;
 mod vm;
 use crate::intrepreter::Intrepreter;
 use crate::scanner::Scanner;
+use assembler::assemble;
+use disassembler::disassemble;
 use error::LoxResult;
+use function::ByteCodeFunction;
 use generator::ByteCodeGenerator;
 use parser::Parser;
+use resolver::Resolver;
 use vm::Vm;
 
 fn run_prompt() {
@@ -48,34 +63,63 @@ fn run_prompt() {
     }
 }
 
-fn run_file(file_name: String) {
-    let file_path = format!("examples/{}", file_name);
-    let contents = fs::read_to_string(file_path).expect("Should have been able to read the file");
+/// Reads a script from `examples/`: `.loxc` files are loaded as they were
+/// written by `compile`, bytecode listings like the ones `--disassemble`
+/// prints (`.lasm`) are assembled and anything else is compiled as Lox,
+/// unless it is unchanged since its bytecode was cached.
+fn load_script(file_name: &str) -> LoxResult<ByteCodeFunction> {
+    let file_path = Path::new("examples").join(file_name);
+    if file_name.ends_with(".loxc") {
+        let bytes = fs::read(file_path).expect("Should have been able to read the file");
+        return bytecode_file::decode(&bytes);
+    }
+    let contents = fs::read_to_string(&file_path).expect("Should have been able to read the file");
+    if file_name.ends_with(".lasm") {
+        assemble(&contents)
+    } else {
+        cache::load_or_compile(&file_path, &contents, || compile(contents.clone()))
+    }
+}
+
+fn run_file(file_name: &str, show_bytecode: bool) {
+    if let Err(error) = load_script(file_name).and_then(|script| run_vm(script, show_bytecode)) {
+        error.report();
+    }
+}
 
-    run_vm(contents);
+/// Writes the bytecode of `file_name` next to it, as a `.loxc` file.
+fn compile_file(file_name: &str) {
+    match load_script(file_name) {
+        Ok(script) => {
+            let output = Path::new(file_name).with_extension("loxc");
+            fs::write(
+                Path::new("examples").join(&output),
+                bytecode_file::encode(&script),
+            )
+            .expect("Should have been able to write the compiled file");
+            println!("Compiled {} to {}", file_name, output.display());
+        }
+        Err(error) => error.report(),
+    }
 }
 
-fn run_vm(source: String) {
+fn compile(source: String) -> LoxResult<ByteCodeFunction> {
     let mut generator = ByteCodeGenerator::default();
     let mut scanner = Scanner::new(source);
     let tokens = scanner.scan_tokens();
     let mut parser = Parser::new(&tokens);
-    let statements = parser.parse().unwrap();
-    dbg!(&statements);
-    generator.intrepret(&statements).unwrap();
-    println!(
-        "Len: {} Bytecodes: {:#?}",
-        generator.bytecodes.len(),
-        &generator.bytecodes
-    );
-    let mut vm = Vm::new(generator.bytecodes);
-    vm.interpret();
+    let statements = parser.parse()?;
+    Resolver::default().resolve(&statements)?;
+    generator.intrepret(&statements)?;
+    Ok(generator.script())
+}
 
-    // let mut registers: Vec<_> = vm.registers.iter().collect();
-    // registers.sort_by(|a, b| a.0.cmp(&b.0));
-    // for (i, r) in registers.iter().enumerate() {
-    //     println!("R{}: {}", i + 1, r.1);
-    // }
+fn run_vm(script: ByteCodeFunction, show_bytecode: bool) -> LoxResult<()> {
+    if show_bytecode {
+        print!("{}", disassemble(&script));
+    }
+    let mut vm = Vm::new(script);
+    vm.interpret()
 }
 
 fn run(source: String, intrepreter: &mut Intrepreter) -> LoxResult<()> {
@@ -83,17 +127,26 @@ fn run(source: String, intrepreter: &mut Intrepreter) -> LoxResult<()> {
     let tokens = scanner.scan_tokens();
     let mut parser = Parser::new(&tokens);
     let statements = parser.parse()?;
-    dbg!(&statements);
+    Resolver::default().resolve(&statements)?;
     intrepreter.intrepret(&statements)?;
     Ok(())
 }
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() <= 1 {
-        run_prompt();
-    } else {
-        let file_name = &args[1];
-        run_file(file_name.to_string());
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    // `--disassemble` prints the generated bytecode before running it.
+    let show_bytecode = args.iter().any(|arg| arg == "--disassemble");
+    let mut args = args
+        .iter()
+        .filter(|arg| *arg != "--disassemble")
+        .map(String::as_str);
+    match (args.next(), args.next()) {
+        (Some("compile"), Some(file_name)) => compile_file(file_name),
+        (Some("run"), Some(file_name)) => run_file(file_name, show_bytecode),
+        (Some("compile" | "run"), None) => {
+            eprintln!("Usage: [compile | run] [--disassemble] <file>")
+        }
+        (Some(file_name), _) => run_file(file_name, show_bytecode),
+        (None, _) => run_prompt(),
     }
 }
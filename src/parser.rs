@@ -1,7 +1,10 @@
+use std::cell::Cell;
+
 use crate::{
     ast::{
-        Assign, Binary, BlockStmt, Expr, ExpressionStmt, Grouping, IfStmt, Literal, Logical,
-        PrintStmt, Stmt, Unary, VarStmt, Variable, WhileStmt,
+        Assign, Binary, BlockStmt, Call, ClassStmt, Expr, ExpressionStmt, FunctionStmt, Get,
+        Grouping, IfStmt, Literal, Logical, PrintStmt, ReturnStmt, Set, Stmt, Super, This, Unary,
+        VarStmt, Variable, WhileStmt,
     },
     error::{Error, LoxErrors, LoxResult},
     token::Token,
@@ -17,11 +20,14 @@ pub trait ParseExpr {
     fn term(&mut self) -> LoxResult<Expr>;
     fn factor(&mut self) -> LoxResult<Expr>;
     fn unary(&mut self) -> LoxResult<Expr>;
+    fn call(&mut self) -> LoxResult<Expr>;
     fn primary(&mut self) -> LoxResult<Expr>;
     fn or(&mut self) -> LoxResult<Expr>;
     fn and(&mut self) -> LoxResult<Expr>;
 }
 
+const MAX_ARGUMENTS: usize = 255;
+
 pub struct Parser<'a> {
     tokens: &'a Vec<Token>,
     current: usize,
@@ -75,6 +81,10 @@ impl<'a> Parser<'a> {
             return self.print_statement();
         }
 
+        if self.match_token(&[Return]) {
+            return self.return_statement();
+        }
+
         if self.match_token(&[For]) {
             return self.for_statement();
         }
@@ -124,7 +134,6 @@ impl<'a> Parser<'a> {
         if !self.check(&RightParen) {
             increment = Some(self.expression()?);
         }
-        dbg!(&increment);
         self.consume(RightParen, "Expect ')' after for clauses")?;
 
         let mut body = self.statement()?;
@@ -140,16 +149,19 @@ impl<'a> Parser<'a> {
             });
         }
 
-        if let Some(condition) = condition {
-            body = Stmt::WhileStmt(WhileStmt {
-                condition,
-                body: Box::new(body),
+        // A missing condition loops forever.
+        let condition = condition.unwrap_or(Expr::Literal(Literal {
+            value: Some(Value::Boolean(true)),
+            line: self.previous().line,
+        }));
+        body = Stmt::WhileStmt(WhileStmt {
+            condition,
+            body: Box::new(body),
+        });
+        if let Some(initializer) = initializer {
+            body = Stmt::BlockStmt(BlockStmt {
+                statements: vec![initializer, body],
             });
-            if let Some(initializer) = initializer {
-                body = Stmt::BlockStmt(BlockStmt {
-                    statements: vec![initializer, body],
-                });
-            }
         }
         Ok(body)
     }
@@ -193,9 +205,22 @@ impl<'a> Parser<'a> {
 
     fn print_statement(&mut self) -> LoxResult<Stmt> {
         let expr = self.expression()?;
+        self.consume(Semicolon, "Expect ';' after value.")?;
         Ok(Stmt::PrintStmt(PrintStmt { expression: expr }))
     }
 
+    fn return_statement(&mut self) -> LoxResult<Stmt> {
+        let keyword = self.previous().to_owned();
+        let mut value = None;
+
+        if !self.check(&Semicolon) {
+            value = Some(self.expression()?);
+        }
+        self.consume(Semicolon, "Expect ';' after return value.")?;
+
+        Ok(Stmt::ReturnStmt(ReturnStmt { keyword, value }))
+    }
+
     pub fn parse(&mut self) -> LoxResult<Vec<Stmt>> {
         let mut statements = vec![];
         while !self.is_at_end() {
@@ -205,12 +230,70 @@ impl<'a> Parser<'a> {
     }
 
     fn declaration(&mut self) -> LoxResult<Stmt> {
+        if self.match_token(&[Class]) {
+            return self.class_declaration();
+        }
+        if self.match_token(&[Fun]) {
+            return Ok(Stmt::FunctionStmt(self.function("function")?));
+        }
         if self.match_token(&[Var]) {
             return self.var_declaration();
         }
         self.statement()
     }
 
+    fn class_declaration(&mut self) -> LoxResult<Stmt> {
+        let name = self.consume(Identifier, "Expect class name.")?.clone();
+
+        let mut superclass = None;
+        if self.match_token(&[Less]) {
+            let name = self.consume(Identifier, "Expect superclass name.")?.clone();
+            superclass = Some(Variable {
+                name,
+                depth: Cell::default(),
+            });
+        }
+
+        self.consume(LeftBrace, "Expect '{' before class body.")?;
+        let mut methods = vec![];
+        while !self.check(&RightBrace) && !self.is_at_end() {
+            methods.push(self.function("method")?);
+        }
+        self.consume(RightBrace, "Expect '}' after class body.")?;
+
+        Ok(Stmt::ClassStmt(ClassStmt {
+            name,
+            superclass,
+            methods,
+        }))
+    }
+
+    fn function(&mut self, kind: &str) -> LoxResult<FunctionStmt> {
+        let name = self
+            .consume(Identifier, &format!("Expect {kind} name."))?
+            .clone();
+        self.consume(LeftParen, &format!("Expect '(' after {kind} name."))?;
+
+        let mut params = vec![];
+        if !self.check(&RightParen) {
+            loop {
+                if params.len() >= MAX_ARGUMENTS {
+                    return Err(self.error(self.peek(), "Can't have more than 255 parameters."));
+                }
+                params.push(self.consume(Identifier, "Expect parameter name.")?.clone());
+                if !self.match_token(&[Comma]) {
+                    break;
+                }
+            }
+        }
+        self.consume(RightParen, "Expect ')' after parameters.")?;
+
+        self.consume(LeftBrace, &format!("Expect '{{' before {kind} body."))?;
+        let body = self.block()?;
+
+        Ok(FunctionStmt { name, params, body })
+    }
+
     fn var_declaration(&mut self) -> LoxResult<Stmt> {
         let name = self.consume(Identifier, "Expect variable name")?.clone();
         let mut initializer = None;
@@ -218,6 +301,7 @@ impl<'a> Parser<'a> {
         if self.match_token(&[Equal]) {
             initializer = Some(self.expression()?);
         }
+        self.consume(Semicolon, "Expect ';' after variable declaration.")?;
 
         Ok(Stmt::VarStmt(VarStmt { initializer, name }))
     }
@@ -237,6 +321,30 @@ impl<'a> Parser<'a> {
     fn error(&self, peek: &Token, message: &str) -> LoxErrors {
         LoxErrors::ParseError(Error::new(peek.line, message.to_string()))
     }
+
+    fn finish_call(&mut self, callee: Expr) -> LoxResult<Expr> {
+        let mut arguments = vec![];
+        if !self.check(&RightParen) {
+            loop {
+                if arguments.len() >= MAX_ARGUMENTS {
+                    return Err(self.error(self.peek(), "Can't have more than 255 arguments."));
+                }
+                arguments.push(self.expression()?);
+                if !self.match_token(&[Comma]) {
+                    break;
+                }
+            }
+        }
+        let paren = self
+            .consume(RightParen, "Expect ')' after arguments.")?
+            .clone();
+
+        Ok(Expr::Call(Call {
+            callee: Box::new(callee),
+            paren,
+            arguments,
+        }))
+    }
 }
 
 impl<'a> ParseExpr for Parser<'a> {
@@ -252,10 +360,18 @@ impl<'a> ParseExpr for Parser<'a> {
             let value = self.assignment()?;
 
             match expr {
-                Expr::Variable(Variable { name }) => {
+                Expr::Variable(Variable { name, .. }) => {
                     return Ok(Expr::Assign(Assign {
                         name,
                         value: Box::new(value),
+                        depth: Cell::default(),
+                    }));
+                }
+                Expr::Get(Get { object, name }) => {
+                    return Ok(Expr::Set(Set {
+                        object,
+                        name,
+                        value: Box::new(value),
                     }));
                 }
                 _ => return Err(self.error(&equals, "Invalid assignment target")),
@@ -301,21 +417,43 @@ impl<'a> ParseExpr for Parser<'a> {
         if self.match_token(&[False]) {
             return Ok(Expr::Literal(Literal {
                 value: Some(Value::Boolean(false)),
+                line: self.previous().line,
             }));
         }
         if self.match_token(&[True]) {
             return Ok(Expr::Literal(Literal {
                 value: Some(Value::Boolean(true)),
+                line: self.previous().line,
             }));
         }
         if self.match_token(&[Nil]) {
             return Ok(Expr::Literal(Literal {
                 value: Some(Value::Nil),
+                line: self.previous().line,
+            }));
+        }
+        if self.match_token(&[TokenType::This]) {
+            return Ok(Expr::This(This {
+                keyword: self.previous().to_owned(),
+                depth: Cell::default(),
+            }));
+        }
+        if self.match_token(&[TokenType::Super]) {
+            let keyword = self.previous().to_owned();
+            self.consume(Dot, "Expect '.' after 'super'.")?;
+            let method = self
+                .consume(Identifier, "Expect superclass method name.")?
+                .clone();
+            return Ok(Expr::Super(Super {
+                keyword,
+                method,
+                depth: Cell::default(),
             }));
         }
         if self.match_token(&[Number, TokenType::String]) {
             return Ok(Expr::Literal(Literal {
                 value: self.previous().literal.to_owned(),
+                line: self.previous().line,
             }));
         }
         if self.match_token(&[LeftParen]) {
@@ -332,7 +470,10 @@ impl<'a> ParseExpr for Parser<'a> {
                 return Ok(Expr::Assign(Assign {
                     name: identifier.clone(),
                     value: Box::new(Expr::Binary(Binary {
-                        left: Box::new(Expr::Variable(Variable { name: identifier })),
+                        left: Box::new(Expr::Variable(Variable {
+                            name: identifier.clone(),
+                            depth: Cell::default(),
+                        })),
                         operator: Token {
                             type_: Plus,
                             lexeme: "+".into(),
@@ -341,11 +482,16 @@ impl<'a> ParseExpr for Parser<'a> {
                         },
                         right: Box::new(Expr::Literal(Literal {
                             value: Some(Value::Number(1.0)),
+                            line: self.previous().line,
                         })),
                     })),
+                    depth: Cell::default(),
                 }));
             }
-            return Ok(Expr::Variable(Variable { name: identifier }));
+            Ok(Expr::Variable(Variable {
+                name: identifier,
+                depth: Cell::default(),
+            }))
         } else {
             Err(LoxErrors::ParseError(Error::new(
                 self.previous().line,
@@ -436,6 +582,28 @@ impl<'a> ParseExpr for Parser<'a> {
             }));
         }
 
-        self.primary()
+        self.call()
+    }
+
+    fn call(&mut self) -> LoxResult<Expr> {
+        let mut expr = self.primary()?;
+
+        loop {
+            if self.match_token(&[LeftParen]) {
+                expr = self.finish_call(expr)?;
+            } else if self.match_token(&[Dot]) {
+                let name = self
+                    .consume(Identifier, "Expect property name after '.'.")?
+                    .clone();
+                expr = Expr::Get(Get {
+                    object: Box::new(expr),
+                    name,
+                });
+            } else {
+                break;
+            }
+        }
+
+        Ok(expr)
     }
 }
@@ -0,0 +1,400 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+
+use crate::ast::{
+    Assign, Binary, BlockStmt, Call, ClassStmt, Expr, ExpressionStmt, FunctionStmt, Get, Grouping,
+    IfStmt, Literal as LiteralExp, Logical, PrintStmt, ReturnStmt, Set, Stmt, Super, This, Unary,
+    VarStmt, Variable, VisitorExpr, VisitorStmt, WhileStmt,
+};
+use crate::error::{Error, LoxErrors, LoxResult};
+use crate::token::Token;
+use crate::tools::*;
+
+#[derive(Clone, Copy, PartialEq)]
+enum FunctionType {
+    None,
+    Function,
+    Method,
+    Initializer,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ClassType {
+    None,
+    Class,
+    Subclass,
+}
+
+/// Static pass run between the parser and either backend. It binds every
+/// variable use to its declaration by recording, in the node's `depth`, how
+/// many scopes separate the two, and reports scope errors up front.
+pub struct Resolver {
+    /// One map per scope enclosing the code being resolved, innermost last.
+    /// A name maps to `false` between its declaration and the end of its
+    /// initializer. Globals are not tracked.
+    scopes: Vec<HashMap<String, bool>>,
+    current_function: FunctionType,
+    current_class: ClassType,
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Self {
+            scopes: vec![],
+            current_function: FunctionType::None,
+            current_class: ClassType::None,
+        }
+    }
+}
+
+impl Resolver {
+    pub fn resolve(&mut self, statements: &[Stmt]) -> LoxResult<()> {
+        statements
+            .iter()
+            .try_for_each(|stmt| self.resolve_stmt(stmt))
+    }
+
+    fn resolve_stmt(&mut self, stmt: &Stmt) -> LoxResult<()> {
+        stmt.accept(self)
+    }
+
+    fn resolve_expr(&mut self, expr: &Expr) -> LoxResult<()> {
+        expr.accept(self)
+    }
+
+    fn begin_scope(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    fn end_scope(&mut self) {
+        self.scopes.pop();
+    }
+
+    fn declare(&mut self, name: &Token) -> LoxResult<()> {
+        let Some(scope) = self.scopes.last_mut() else {
+            return Ok(());
+        };
+        if scope.contains_key(&name.lexeme) {
+            return Err(error(
+                name,
+                "Already a variable with this name in this scope.",
+            ));
+        }
+        scope.insert(name.lexeme.clone(), false);
+        Ok(())
+    }
+
+    fn define(&mut self, name: &Token) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.lexeme.clone(), true);
+        }
+    }
+
+    /// Records in `depth` how far out `name` is declared. Names that no
+    /// enclosing scope declares are left as globals.
+    fn resolve_local(&mut self, name: &Token, depth: &Cell<Option<usize>>) {
+        let found = self
+            .scopes
+            .iter()
+            .rev()
+            .position(|scope| scope.contains_key(&name.lexeme));
+        depth.set(found);
+    }
+
+    fn resolve_function(&mut self, function: &FunctionStmt, kind: FunctionType) -> LoxResult<()> {
+        let enclosing = std::mem::replace(&mut self.current_function, kind);
+        self.begin_scope();
+        let result = function
+            .params
+            .iter()
+            .try_for_each(|param| {
+                self.declare(param)?;
+                self.define(param);
+                Ok(())
+            })
+            .and_then(|()| self.resolve(&function.body));
+        self.end_scope();
+        self.current_function = enclosing;
+        result
+    }
+
+    fn resolve_class(&mut self, stmt: &ClassStmt) -> LoxResult<()> {
+        if let Some(superclass) = &stmt.superclass {
+            if superclass.name.lexeme == stmt.name.lexeme {
+                return Err(error(
+                    &superclass.name,
+                    "A class can't inherit from itself.",
+                ));
+            }
+            self.current_class = ClassType::Subclass;
+            self.visit_variable_expr(superclass)?;
+
+            self.begin_scope();
+            self.scopes
+                .last_mut()
+                .unwrap()
+                .insert("super".to_string(), true);
+        }
+
+        self.begin_scope();
+        self.scopes
+            .last_mut()
+            .unwrap()
+            .insert("this".to_string(), true);
+        let result = stmt.methods.iter().try_for_each(|method| {
+            let kind = if method.name.lexeme == "init" {
+                FunctionType::Initializer
+            } else {
+                FunctionType::Method
+            };
+            self.resolve_function(method, kind)
+        });
+        self.end_scope();
+
+        if stmt.superclass.is_some() {
+            self.end_scope();
+        }
+        result
+    }
+}
+
+impl VisitorExpr for Resolver {
+    type Result = LoxResult<()>;
+
+    fn visit_logical_expr(&mut self, expr: &Logical) -> Self::Result {
+        self.resolve_expr(&expr.left)?;
+        self.resolve_expr(&expr.right)
+    }
+
+    fn visit_assign_expr(&mut self, expr: &Assign) -> Self::Result {
+        self.resolve_expr(&expr.value)?;
+        self.resolve_local(&expr.name, &expr.depth);
+        Ok(())
+    }
+
+    fn visit_variable_expr(&mut self, expr: &Variable) -> Self::Result {
+        let declared = self
+            .scopes
+            .last()
+            .and_then(|scope| scope.get(&expr.name.lexeme));
+        if declared == Some(&false) {
+            return Err(error(
+                &expr.name,
+                "Can't read local variable in its own initializer.",
+            ));
+        }
+        self.resolve_local(&expr.name, &expr.depth);
+        Ok(())
+    }
+
+    fn visit_call_expr(&mut self, expr: &Call) -> Self::Result {
+        self.resolve_expr(&expr.callee)?;
+        expr.arguments
+            .iter()
+            .try_for_each(|argument| self.resolve_expr(argument))
+    }
+
+    fn visit_get_expr(&mut self, expr: &Get) -> Self::Result {
+        self.resolve_expr(&expr.object)
+    }
+
+    fn visit_set_expr(&mut self, expr: &Set) -> Self::Result {
+        self.resolve_expr(&expr.value)?;
+        self.resolve_expr(&expr.object)
+    }
+
+    fn visit_this_expr(&mut self, expr: &This) -> Self::Result {
+        if self.current_class == ClassType::None {
+            return Err(error(&expr.keyword, "Can't use 'this' outside of a class."));
+        }
+        self.resolve_local(&expr.keyword, &expr.depth);
+        Ok(())
+    }
+
+    fn visit_super_expr(&mut self, expr: &Super) -> Self::Result {
+        match self.current_class {
+            ClassType::None => Err(error(
+                &expr.keyword,
+                "Can't use 'super' outside of a class.",
+            )),
+            ClassType::Class => Err(error(
+                &expr.keyword,
+                "Can't use 'super' in a class with no superclass.",
+            )),
+            ClassType::Subclass => {
+                self.resolve_local(&expr.keyword, &expr.depth);
+                Ok(())
+            }
+        }
+    }
+
+    fn visit_literal_expr(&mut self, _expr: &LiteralExp) -> Self::Result {
+        Ok(())
+    }
+
+    fn visit_grouping_expr(&mut self, expr: &Grouping) -> Self::Result {
+        self.resolve_expr(&expr.expression)
+    }
+
+    fn visit_unary_expr(&mut self, expr: &Unary) -> Self::Result {
+        self.resolve_expr(&expr.right)
+    }
+
+    fn visit_binary_exp(&mut self, expr: &Binary) -> Self::Result {
+        self.resolve_expr(&expr.left)?;
+        self.resolve_expr(&expr.right)
+    }
+}
+
+impl VisitorStmt for Resolver {
+    type Result = LoxResult<()>;
+
+    fn visit_if_stmt(&mut self, stmt: &IfStmt) -> Self::Result {
+        self.resolve_expr(&stmt.condition)?;
+        self.resolve_stmt(&stmt.then_branch)?;
+        if let Some(else_branch) = &stmt.else_branch {
+            self.resolve_stmt(else_branch)?;
+        }
+        Ok(())
+    }
+
+    fn visit_expression_stmt(&mut self, stmt: &ExpressionStmt) -> Self::Result {
+        self.resolve_expr(&stmt.expression)
+    }
+
+    fn visit_print_stmt(&mut self, stmt: &PrintStmt) -> Self::Result {
+        self.resolve_expr(&stmt.expression)
+    }
+
+    fn visit_var_stmt(&mut self, stmt: &VarStmt) -> Self::Result {
+        self.declare(&stmt.name)?;
+        if let Some(initializer) = &stmt.initializer {
+            self.resolve_expr(initializer)?;
+        }
+        self.define(&stmt.name);
+        Ok(())
+    }
+
+    fn visit_block_stmt(&mut self, stmt: &BlockStmt) -> Self::Result {
+        self.begin_scope();
+        let result = self.resolve(&stmt.statements);
+        self.end_scope();
+        result
+    }
+
+    fn visit_while_stmt(&mut self, stmt: &WhileStmt) -> Self::Result {
+        self.resolve_expr(&stmt.condition)?;
+        self.resolve_stmt(&stmt.body)
+    }
+
+    fn visit_function_stmt(&mut self, stmt: &FunctionStmt) -> Self::Result {
+        // Declared and defined before the body so the function can recurse.
+        self.declare(&stmt.name)?;
+        self.define(&stmt.name);
+        self.resolve_function(stmt, FunctionType::Function)
+    }
+
+    fn visit_return_stmt(&mut self, stmt: &ReturnStmt) -> Self::Result {
+        if self.current_function == FunctionType::None {
+            return Err(error(&stmt.keyword, "Can't return from top-level code."));
+        }
+        if let Some(value) = &stmt.value {
+            if self.current_function == FunctionType::Initializer {
+                return Err(error(
+                    &stmt.keyword,
+                    "Can't return a value from an initializer.",
+                ));
+            }
+            self.resolve_expr(value)?;
+        }
+        Ok(())
+    }
+
+    fn visit_class_stmt(&mut self, stmt: &ClassStmt) -> Self::Result {
+        self.declare(&stmt.name)?;
+        self.define(&stmt.name);
+
+        let enclosing = std::mem::replace(&mut self.current_class, ClassType::Class);
+        let result = self.resolve_class(stmt);
+        self.current_class = enclosing;
+        result
+    }
+}
+
+fn error(token: &Token, message: &str) -> LoxErrors {
+    LoxErrors::ParseError(Error::new(token.line, message.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::scanner::Scanner;
+
+    fn resolve_source(source: &str) -> LoxResult<Vec<Stmt>> {
+        let tokens = Scanner::new(source.to_string()).scan_tokens();
+        let statements = Parser::new(&tokens).parse()?;
+        Resolver::default().resolve(&statements)?;
+        Ok(statements)
+    }
+
+    fn error_message(source: &str) -> String {
+        resolve_source(source).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_depth_of_each_variable_use() {
+        let statements = resolve_source(
+            "var global = 1;
+             fun outer(a) { { var b = a; print global + b; } }",
+        )
+        .unwrap();
+        let Stmt::FunctionStmt(outer) = &statements[1] else {
+            panic!("expected a function");
+        };
+        let Stmt::BlockStmt(block) = &outer.body[0] else {
+            panic!("expected a block");
+        };
+        let Stmt::VarStmt(VarStmt {
+            initializer: Some(Expr::Variable(a)),
+            ..
+        }) = &block.statements[0]
+        else {
+            panic!("expected a variable initializer");
+        };
+        assert_eq!(a.depth.get(), Some(1));
+
+        let Stmt::PrintStmt(PrintStmt {
+            expression: Expr::Binary(sum),
+        }) = &block.statements[1]
+        else {
+            panic!("expected a print of a sum");
+        };
+        let (Expr::Variable(global), Expr::Variable(b)) = (&*sum.left, &*sum.right) else {
+            panic!("expected two variables");
+        };
+        assert_eq!(global.depth.get(), None);
+        assert_eq!(b.depth.get(), Some(0));
+    }
+
+    #[test]
+    fn test_scope_errors() {
+        assert!(error_message("{ var a = 1; { var a = a; } }")
+            .ends_with("Can't read local variable in its own initializer."));
+        assert!(error_message("fun f() { var a = 1; var a = 2; }")
+            .ends_with("Already a variable with this name in this scope."));
+        assert!(error_message("return 1;").ends_with("Can't return from top-level code."));
+        // Globals may be redeclared and read in their own initializer.
+        assert!(resolve_source("var a = 1; var a = a;").is_ok());
+    }
+
+    #[test]
+    fn test_class_errors() {
+        assert!(error_message("print this;").ends_with("Can't use 'this' outside of a class."));
+        assert!(error_message("class A { f() { return super.f; } }")
+            .ends_with("Can't use 'super' in a class with no superclass."));
+        assert!(error_message("class A < A {}").ends_with("A class can't inherit from itself."));
+        assert!(error_message("class A { init() { return 1; } }")
+            .ends_with("Can't return a value from an initializer."));
+    }
+}
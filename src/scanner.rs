@@ -168,7 +168,7 @@ impl Scanner {
             return '\0';
         }
 
-        return self.source.chars().nth(self.current + 1).unwrap();
+        self.source.chars().nth(self.current + 1).unwrap()
     }
 
     fn add_string(&mut self) {
@@ -201,7 +201,7 @@ impl Scanner {
     }
 
     fn is_digit(&self, c: char) -> bool {
-        ('0'..='9').contains(&c)
+        c.is_ascii_digit()
     }
 
     fn peek(&self) -> char {
@@ -35,6 +35,7 @@ pub enum TokenType {
     Nil,
     Or,
     Print,
+    #[allow(dead_code)]
     Console,
     Return,
     Super,
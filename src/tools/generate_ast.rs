@@ -143,6 +143,7 @@ macro_rules! define_ast {
         }
 
         // Create an Enum for the structs
+        #[allow(clippy::enum_variant_names)]
         #[derive(Debug, Clone, PartialEq)]
         pub enum $enum_name {
             $($name($name),)*
@@ -1,5 +1,4 @@
 mod generate_ast;
 mod traits;
 
-pub use generate_ast::*;
 pub use traits::*;
@@ -1,10 +1,14 @@
+use std::cell::RefCell;
 use std::cmp::Ordering;
+use std::rc::Rc;
 use std::{
     fmt::{self, Debug},
-    ops::{Add, Div, Mul, Sub},
+    ops::{Add, Div, Mul, Neg, Sub},
 };
 
+use crate::class::{LoxClass, LoxInstance};
 use crate::error::{Error, LoxErrors};
+use crate::function::{BoundMethod, Closure, LoxFunction};
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Value {
@@ -13,21 +17,26 @@ pub enum Value {
     Nil,
     Number(f64),
     String(String),
+    Function(Rc<LoxFunction>),
+    Closure(Rc<Closure>),
+    BoundMethod(Rc<BoundMethod>),
+    Class(Rc<LoxClass>),
+    Instance(Rc<RefCell<LoxInstance>>),
     ArithmeticError,
 }
 
-impl Value {
-    fn is_number(&self) -> bool {
-        matches!(self, Self::Number(_))
+/// Truthiness shared by the tree-walker and the `Vm`.
+pub fn is_truthy(object: &Value) -> bool {
+    match *object {
+        Value::Nil => false,
+        Value::Boolean(b) => b,
+        _ => true,
     }
+}
 
-    pub fn as_number(&self) -> f64 {
-        if let Self::Number(number) = self {
-            *number
-        } else {
-            0.
-        }
-    }
+/// Equality shared by the tree-walker and the `Vm`.
+pub fn is_equal(a: &Value, b: &Value) -> bool {
+    a == b
 }
 
 impl fmt::Display for Value {
@@ -37,6 +46,11 @@ impl fmt::Display for Value {
             Self::Nil => write!(f, "nil"),
             Self::Number(n) => write!(f, "{n}"),
             Self::String(s) => write!(f, "{s}"),
+            Self::Function(function) => write!(f, "{function:?}"),
+            Self::Closure(closure) => write!(f, "{closure:?}"),
+            Self::BoundMethod(method) => write!(f, "{method:?}"),
+            Self::Class(class) => write!(f, "{class:?}"),
+            Self::Instance(instance) => write!(f, "{:?}", instance.borrow()),
             Self::ArithmeticError => write!(f, "Unable to evalute arithmetic expression"),
             Self::Undefined => write!(f, "undefined"),
         }
@@ -85,6 +99,16 @@ impl Div for Value {
     }
 }
 
+impl Neg for Value {
+    type Output = Self;
+    fn neg(self) -> Self {
+        match self {
+            Self::Number(number) => Self::Number(-number),
+            _ => Self::ArithmeticError,
+        }
+    }
+}
+
 impl PartialOrd for Value {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         match (self, other) {
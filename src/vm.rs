@@ -1,163 +1,1045 @@
-use crate::generator::ByteCode;
-use crate::value::Value;
+use crate::class::{LoxClass, LoxInstance};
+use crate::encoding::{self, Opcode};
+use crate::error::{Error, LoxErrors, LoxResult};
+use crate::function::{BoundMethod, ByteCodeFunction, Closure, Upvalue};
+use crate::value::{is_equal, is_truthy, Value};
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::ops::{Add, Div, Mul, Sub};
+use std::rc::Rc;
 
+/// How deep calls may nest before the VM reports a stack overflow.
+const FRAMES_MAX: usize = 64;
+
+/// The activation record of a function invocation. Every frame owns its
+/// register window, so recursive calls don't clobber each other.
 #[derive(Clone)]
-pub struct Label {
-    pub name: String,
-    pub address: usize,
+struct CallFrame {
+    closure: Rc<Closure>,
+    pc: usize,
+    /// Offset of the instruction that is executing, for error lines.
+    start: usize,
+    /// Sized up front from the function's `register_count`, so registers
+    /// are plain indices.
+    registers: Vec<Value>,
 }
 
-impl Label {
-    pub fn new(name: String, address: usize) -> Self {
-        Self { name, address }
-    }
-
-    fn get_name(&self) -> &str {
-        &self.name
-    }
-
-    fn get_address(&self) -> usize {
-        self.address
+impl CallFrame {
+    fn new(closure: Rc<Closure>) -> Self {
+        Self {
+            pc: 0,
+            start: 0,
+            registers: vec![Value::Undefined; closure.function.register_count],
+            closure,
+        }
     }
 }
 
-#[derive(Clone, Default)]
-struct Flags {
-    pub less_than: bool,
-    pub greater_than: bool,
-    pub equal: bool,
+#[derive(Clone)]
+pub struct Vm {
+    frames: Vec<CallFrame>,
+    /// The implicit operand and destination of most instructions. A call
+    /// leaves its return value here for the caller.
+    accumulator: Value,
+    /// Width of the operands of the instruction that is executing.
+    width: usize,
+    pub variables: HashMap<String, Value>,
+    /// Upvalues still pointing into a live frame, shared by every closure
+    /// that captures the same variable.
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
 }
 
-impl Flags {
-    pub fn set_less_than(&mut self, value: bool) {
-        self.less_than = value;
+impl Vm {
+    pub fn new(script: ByteCodeFunction) -> Self {
+        Self {
+            frames: vec![CallFrame::new(Rc::new(Closure::new(Rc::new(script))))],
+            accumulator: Value::Undefined,
+            width: 1,
+            variables: HashMap::new(),
+            open_upvalues: vec![],
+        }
     }
 
-    pub fn set_greater_than(&mut self, value: bool) {
-        self.greater_than = value;
+    fn frame(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().unwrap()
     }
 
-    pub fn set_equal(&mut self, value: bool) {
-        self.equal = value;
+    /// Reads the next opcode, skipping a `Wide` or `ExtraWide` prefix after
+    /// noting the width it gives the operands. Running off the end of the
+    /// code returns from the function.
+    fn read_opcode(&mut self) -> Opcode {
+        let frame = self.frame();
+        frame.start = frame.pc;
+        let code = &frame.closure.function.code;
+        let Some(&byte) = code.get(frame.pc) else {
+            return Opcode::Return;
+        };
+        frame.pc += 1;
+        let opcode = Opcode::from_byte(byte).expect("the code was validated when it was built");
+        let (width, opcode) = match opcode {
+            Opcode::Wide | Opcode::ExtraWide => {
+                let prefixed = Opcode::from_byte(code[frame.pc]).unwrap();
+                frame.pc += 1;
+                (if opcode == Opcode::Wide { 2 } else { 4 }, prefixed)
+            }
+            opcode => (1, opcode),
+        };
+        self.width = width;
+        opcode
     }
-}
 
-#[derive(Clone)]
-pub struct Vm {
-    pub registers: HashMap<i32, Value>,
-    pc: usize,
-    flag: bool,
-    stack: Vec<f64>,
-    program: Vec<ByteCode>,
-    labels: HashMap<String, i32>,
-    variables: HashMap<String, Value>,
-}
+    fn read_operand(&mut self) -> usize {
+        let width = self.width;
+        let frame = self.frame();
+        let operand = encoding::read_operand(&frame.closure.function.code, frame.pc, width);
+        frame.pc += width;
+        operand
+    }
 
-impl Vm {
-    pub fn new(bytecodes: Vec<ByteCode>) -> Self {
-        Self {
-            registers: HashMap::new(),
-            pc: 0,
-            flag: false,
-            labels: HashMap::new(),
-            stack: Vec::new(),
-            variables: HashMap::new(),
-            program: bytecodes,
+    fn read_register_operand(&mut self) -> i32 {
+        self.read_operand() as i32
+    }
+
+    /// Reads an operand naming a string constant, such as a variable name.
+    fn read_name(&mut self) -> String {
+        let constant = self.read_operand();
+        match self.frame().closure.function.constants.get(constant) {
+            Value::String(name) => name.clone(),
+            _ => unreachable!("names are string constants"),
         }
     }
 
-    fn read_byte(&mut self) -> ByteCode {
-        let byte = self.program.get(self.pc).unwrap_or(&ByteCode::Return);
-        self.pc += 1;
-        byte.clone()
+    /// Builds a runtime error located at the line of the instruction that
+    /// is currently executing.
+    fn error(&self, message: String) -> LoxErrors {
+        let frame = self.frames.last().unwrap();
+        let line = frame.closure.function.lines.line(frame.start);
+        LoxErrors::RunTimeException(Error::new(line, message))
     }
 
     fn read_register(&mut self, register: i32) -> Value {
-        self.registers.get(&register).unwrap().clone()
+        self.frame().registers[register as usize].clone()
     }
 
     fn write_register(&mut self, register: i32, value: Value) {
-        self.registers.insert(register, value);
+        self.frame().registers[register as usize] = value;
     }
 
-    fn write_label(&mut self, label: String) {
-        self.labels.insert(label, self.pc as i32);
+    fn call(&mut self, callee: i32, arguments: Vec<i32>) -> LoxResult<()> {
+        match self.read_register(callee) {
+            Value::Closure(closure) => self.call_closure(closure, None, arguments),
+            Value::BoundMethod(bound) => self.call_closure(
+                bound.method.clone(),
+                Some(bound.receiver.clone()),
+                arguments,
+            ),
+            Value::Class(class) => {
+                let instance =
+                    Value::Instance(Rc::new(RefCell::new(LoxInstance::new(class.clone()))));
+                match class.find_method("init") {
+                    Some(Value::Closure(initializer)) => {
+                        self.call_closure(initializer, Some(instance), arguments)
+                    }
+                    _ => {
+                        self.check_arity(0, arguments.len())?;
+                        self.accumulator = instance;
+                        Ok(())
+                    }
+                }
+            }
+            _ => Err(self.error("Can only call functions and classes.".to_string())),
+        }
     }
 
-    fn get_label(&self, label: &str) -> i32 {
-        *self.labels.get(label).unwrap()
+    fn call_closure(
+        &mut self,
+        closure: Rc<Closure>,
+        receiver: Option<Value>,
+        arguments: Vec<i32>,
+    ) -> LoxResult<()> {
+        self.check_arity(closure.function.arity, arguments.len())?;
+        if self.frames.len() == FRAMES_MAX {
+            return Err(self.error("Stack overflow.".to_string()));
+        }
+
+        let mut frame = CallFrame::new(closure);
+        if let Some(receiver) = receiver {
+            frame.registers[0] = receiver;
+        }
+        for (index, argument) in arguments.into_iter().enumerate() {
+            let value = self.read_register(argument);
+            frame.registers[index + 1] = value;
+        }
+        self.frames.push(frame);
+        Ok(())
     }
 
-    pub fn interpret(&mut self) {
-        loop {
-            let instruction = self.read_byte();
-            match instruction {
-                ByteCode::Return => break,
-                ByteCode::LoadUndefined(reg) => {
-                    self.write_register(reg, Value::Undefined);
+    fn check_arity(&self, arity: usize, arguments: usize) -> LoxResult<()> {
+        if arguments != arity {
+            return Err(self.error(format!(
+                "Expected {} arguments but got {}.",
+                arity, arguments
+            )));
+        }
+        Ok(())
+    }
+
+    fn get_property(&mut self, name: &str) -> LoxResult<Value> {
+        let Value::Instance(instance) = self.accumulator.clone() else {
+            return Err(self.error("Only instances have properties.".to_string()));
+        };
+        if let Some(value) = instance.borrow().fields.get(name) {
+            return Ok(value.clone());
+        }
+
+        let method = instance.borrow().class.find_method(name);
+        match method {
+            Some(Value::Closure(method)) => Ok(Value::BoundMethod(Rc::new(BoundMethod {
+                receiver: Value::Instance(instance),
+                method,
+            }))),
+            _ => Err(self.error(format!("Undefined property '{name}'."))),
+        }
+    }
+
+    fn set_property(&mut self, object: i32, name: String, value: Value) -> LoxResult<()> {
+        let Value::Instance(instance) = self.read_register(object) else {
+            return Err(self.error("Only instances have fields.".to_string()));
+        };
+        instance.borrow_mut().fields.insert(name, value);
+        Ok(())
+    }
+
+    /// Binds the superclass method `name` of the running method's class to
+    /// the instance in the accumulator.
+    fn get_super(&mut self, name: &str) -> LoxResult<Value> {
+        let home = self
+            .frame()
+            .closure
+            .home
+            .as_ref()
+            .and_then(|home| home.upgrade());
+        let method = home
+            .as_ref()
+            .and_then(|class| class.superclass.as_ref())
+            .and_then(|superclass| superclass.find_method(name));
+        match method {
+            Some(Value::Closure(method)) => Ok(Value::BoundMethod(Rc::new(BoundMethod {
+                receiver: self.accumulator.clone(),
+                method,
+            }))),
+            _ => Err(self.error(format!("Undefined property '{name}'."))),
+        }
+    }
+
+    fn define_method(&mut self, class: i32, name: String) -> LoxResult<()> {
+        let (Value::Class(class), Value::Closure(method)) =
+            (self.read_register(class), self.accumulator.clone())
+        else {
+            return Err(self.error("Only classes have methods.".to_string()));
+        };
+        let method = Closure {
+            function: method.function.clone(),
+            upvalues: method.upvalues.clone(),
+            home: Some(Rc::downgrade(&class)),
+        };
+        class
+            .methods
+            .borrow_mut()
+            .insert(name, Value::Closure(Rc::new(method)));
+        Ok(())
+    }
+
+    /// Pops the current frame and hands `value` to the caller. Returns
+    /// `false` once the top-level script itself has returned.
+    fn return_from_frame(&mut self, value: Value) -> bool {
+        self.close_upvalues(|_| true);
+        self.frames.pop();
+        self.accumulator = value;
+        !self.frames.is_empty()
+    }
+
+    fn new_closure(&mut self, function: Rc<ByteCodeFunction>) -> Value {
+        let frame_index = self.frames.len() - 1;
+        let upvalues = function
+            .upvalues
+            .iter()
+            .map(|descriptor| {
+                if descriptor.is_local {
+                    self.capture_upvalue(frame_index, descriptor.index)
+                } else {
+                    self.frame().closure.upvalues[descriptor.index as usize].clone()
                 }
-                ByteCode::Load(dst, value) => {
-                    self.write_register(dst, value);
+            })
+            .collect();
+        let home = self.frame().closure.home.clone();
+        Value::Closure(Rc::new(Closure {
+            function,
+            upvalues,
+            home,
+        }))
+    }
+
+    fn capture_upvalue(&mut self, frame: usize, register: i32) -> Rc<RefCell<Upvalue>> {
+        let existing = self.open_upvalues.iter().find(|upvalue| {
+            matches!(*upvalue.borrow(), Upvalue::Open { frame: f, register: r } if f == frame && r == register)
+        });
+        if let Some(upvalue) = existing {
+            return upvalue.clone();
+        }
+
+        let upvalue = Rc::new(RefCell::new(Upvalue::Open { frame, register }));
+        self.open_upvalues.push(upvalue.clone());
+        upvalue
+    }
+
+    /// Moves the values of the current frame's open upvalues whose register
+    /// matches `predicate` out of the frame and into the upvalues themselves.
+    fn close_upvalues(&mut self, predicate: impl Fn(i32) -> bool) {
+        let frame_index = self.frames.len() - 1;
+        let mut open_upvalues = std::mem::take(&mut self.open_upvalues);
+        open_upvalues.retain(|upvalue| {
+            let register = match *upvalue.borrow() {
+                Upvalue::Open { frame, register }
+                    if frame == frame_index && predicate(register) =>
+                {
+                    register
                 }
-                ByteCode::Label(label) => {
-                    self.write_label(label);
+                _ => return true,
+            };
+            let value = self.read_register(register);
+            *upvalue.borrow_mut() = Upvalue::Closed(value);
+            false
+        });
+        self.open_upvalues = open_upvalues;
+    }
+
+    fn read_upvalue(&mut self, index: i32) -> Value {
+        let upvalue = self.frame().closure.upvalues[index as usize].clone();
+        let value = match &*upvalue.borrow() {
+            Upvalue::Open { frame, register } => {
+                self.frames[*frame].registers[*register as usize].clone()
+            }
+            Upvalue::Closed(value) => value.clone(),
+        };
+        value
+    }
+
+    fn write_upvalue(&mut self, index: i32, value: Value) {
+        let upvalue = self.frame().closure.upvalues[index as usize].clone();
+        let mut upvalue = upvalue.borrow_mut();
+        match &mut *upvalue {
+            Upvalue::Open { frame, register } => {
+                self.frames[*frame].registers[*register as usize] = value;
+            }
+            Upvalue::Closed(closed) => *closed = value,
+        }
+    }
+
+    /// Replaces the accumulator with `left op accumulator`, where `left` is
+    /// the register read from the instruction, failing with `message` when
+    /// `op` rejects the operand types.
+    fn arithmetic(&mut self, op: fn(Value, Value) -> Value, message: &str) -> LoxResult<()> {
+        let left = self.read_register_operand();
+        let result = op(self.read_register(left), self.accumulator.clone());
+        if result == Value::ArithmeticError {
+            return Err(self.error(message.to_string()));
+        }
+        self.accumulator = result;
+        Ok(())
+    }
+
+    /// Replaces the accumulator with whether `left op accumulator` holds,
+    /// where `left` is the register read from the instruction.
+    fn compare(&mut self, op: fn(&Value, &Value) -> bool) -> LoxResult<()> {
+        let lhs = self.read_register_operand();
+        let (left, right) = (self.read_register(lhs), self.accumulator.clone());
+        if !matches!((&left, &right), (Value::Number(_), Value::Number(_))) {
+            return Err(self.error("Operands must be numbers.".to_string()));
+        }
+        self.accumulator = Value::Boolean(op(&left, &right));
+        Ok(())
+    }
+
+    pub fn interpret(&mut self) -> LoxResult<()> {
+        loop {
+            match self.read_opcode() {
+                Opcode::Wide | Opcode::ExtraWide => {
+                    unreachable!("prefixes are consumed with their opcode")
                 }
-                ByteCode::JumpIfTrue(label) => {
-                    if self.flag {
-                        self.pc = self.get_label(&label) as usize;
+                Opcode::Return => {
+                    let value = std::mem::replace(&mut self.accumulator, Value::Undefined);
+                    if !self.return_from_frame(value) {
+                        return Ok(());
                     }
                 }
-                ByteCode::Add(dst, src1, src2) => {
-                    let result = self.read_register(src1) + self.read_register(src2);
-                    self.write_register(dst, result);
+                Opcode::Call => {
+                    let callee = self.read_register_operand();
+                    let count = self.read_operand();
+                    let arguments = (0..count).map(|_| self.read_register_operand()).collect();
+                    self.call(callee, arguments)?;
+                }
+                Opcode::Closure => {
+                    let index = self.read_operand();
+                    let function = self.frame().closure.function.functions[index].clone();
+                    self.accumulator = self.new_closure(function);
+                }
+                Opcode::LdaUpvalue => {
+                    let index = self.read_register_operand();
+                    self.accumulator = self.read_upvalue(index);
                 }
-                ByteCode::Mul(dst, src1, src2) => {
-                    let result = self.read_register(src1) * self.read_register(src2);
-                    self.write_register(dst, result);
+                Opcode::StaUpvalue => {
+                    let index = self.read_register_operand();
+                    self.write_upvalue(index, self.accumulator.clone());
                 }
-                ByteCode::Sub(dst, src1, src2) => {
-                    let result = self.read_register(src1) - self.read_register(src2);
-                    self.write_register(dst, result);
+                Opcode::CloseUpvalue => {
+                    let register = self.read_register_operand();
+                    self.close_upvalues(|r| r == register);
                 }
-                ByteCode::Div(dst, src1, src2) => {
-                    let result = self.read_register(src1) / self.read_register(src2);
-                    self.write_register(dst, result);
+                Opcode::Class => {
+                    let name = self.read_name();
+                    let has_superclass = self.read_operand() != 0;
+                    let superclass = self.read_register_operand();
+                    let superclass = match has_superclass.then(|| self.read_register(superclass)) {
+                        Some(Value::Class(class)) => Some(class),
+                        Some(_) => {
+                            return Err(self.error("Superclass must be a class.".to_string()))
+                        }
+                        None => None,
+                    };
+                    let class = LoxClass::new(name, superclass);
+                    self.accumulator = Value::Class(Rc::new(class));
                 }
-                ByteCode::SetVariable(src, register) => {
-                    let value = self.read_register(register);
-                    self.variables.insert(src, value);
+                Opcode::Method => {
+                    let class = self.read_register_operand();
+                    let name = self.read_name();
+                    self.define_method(class, name)?;
                 }
-                ByteCode::GetVariable(dest, register) => {
-                    let value = self.variables.get(&dest);
-                    if value.is_some() {
-                        self.write_register(register, value.unwrap().clone());
+                Opcode::GetProperty => {
+                    let name = self.read_name();
+                    self.accumulator = self.get_property(&name)?;
+                }
+                Opcode::SetProperty => {
+                    let object = self.read_register_operand();
+                    let name = self.read_name();
+                    self.set_property(object, name, self.accumulator.clone())?;
+                }
+                Opcode::GetSuper => {
+                    let name = self.read_name();
+                    self.accumulator = self.get_super(&name)?;
+                }
+                Opcode::Ldar => {
+                    let src = self.read_register_operand();
+                    self.accumulator = self.read_register(src);
+                }
+                Opcode::Star => {
+                    let dst = self.read_register_operand();
+                    self.write_register(dst, self.accumulator.clone());
+                }
+                Opcode::Move => {
+                    let dst = self.read_register_operand();
+                    let src = self.read_register_operand();
+                    let value = self.read_register(src);
+                    self.write_register(dst, value);
+                }
+                Opcode::LdaUndefined => self.accumulator = Value::Undefined,
+                Opcode::LdaNil => self.accumulator = Value::Nil,
+                Opcode::LdaConstant => {
+                    let constant = self.read_operand();
+                    self.accumulator = self
+                        .frame()
+                        .closure
+                        .function
+                        .constants
+                        .get(constant)
+                        .clone();
+                }
+                Opcode::Jump => {
+                    let offset = self.read_operand();
+                    self.frame().pc = offset;
+                }
+                Opcode::JumpIfFalse => {
+                    let offset = self.read_operand();
+                    if !is_truthy(&self.accumulator) {
+                        self.frame().pc = offset;
+                    }
+                }
+                Opcode::JumpIfTrue => {
+                    let offset = self.read_operand();
+                    if is_truthy(&self.accumulator) {
+                        self.frame().pc = offset;
                     }
                 }
-                ByteCode::Print(register) => {
-                    let value = self.read_register(register);
-                    println!("{}", value);
+                Opcode::Add => {
+                    self.arithmetic(Value::add, "Operands must be two numbers or two strings.")?;
+                }
+                Opcode::Mul => self.arithmetic(Value::mul, "Operands must be numbers.")?,
+                Opcode::Sub => self.arithmetic(Value::sub, "Operands must be numbers.")?,
+                Opcode::Div => self.arithmetic(Value::div, "Operands must be numbers.")?,
+                Opcode::Equal => {
+                    let src = self.read_register_operand();
+                    let result = is_equal(&self.read_register(src), &self.accumulator);
+                    self.accumulator = Value::Boolean(result);
                 }
-                ByteCode::TestLessThan(lhs, rhs) => {
-                    let result = self.read_register(lhs) < self.read_register(rhs);
-                    self.flag = result;
+                Opcode::NotEqual => {
+                    let src = self.read_register_operand();
+                    let result = is_equal(&self.read_register(src), &self.accumulator);
+                    self.accumulator = Value::Boolean(!result);
                 }
-                ByteCode::TestLessEqThan(lhs, rhs) => {
-                    let result = self.read_register(lhs) <= self.read_register(rhs);
-                    self.flag = result;
+                Opcode::Negate => {
+                    let result = -self.accumulator.clone();
+                    if result == Value::ArithmeticError {
+                        return Err(self.error("Operand must be a number.".to_string()));
+                    }
+                    self.accumulator = result;
                 }
-                ByteCode::TestGreaterThan(lhs, rhs) => {
-                    let result = self.read_register(lhs) > self.read_register(rhs);
-                    self.flag = result;
+                Opcode::LogicalNot => {
+                    self.accumulator = Value::Boolean(!is_truthy(&self.accumulator));
+                }
+                Opcode::StaGlobal => {
+                    let name = self.read_name();
+                    match self.variables.get_mut(&name) {
+                        Some(value) => *value = self.accumulator.clone(),
+                        None => return Err(self.error(format!("Undefined variable '{name}'."))),
+                    }
                 }
-                ByteCode::TestGreaterEqThan(lhs, rhs) => {
-                    let result = self.read_register(lhs) >= self.read_register(rhs);
-                    self.flag = result;
+                Opcode::DefineGlobal => {
+                    let name = self.read_name();
+                    self.variables.insert(name, self.accumulator.clone());
                 }
-                _ => {
-                    break;
+                Opcode::LdaGlobal => {
+                    let name = self.read_name();
+                    match self.variables.get(&name) {
+                        Some(value) => self.accumulator = value.clone(),
+                        None => return Err(self.error(format!("Undefined variable '{name}'."))),
+                    }
                 }
+                Opcode::Print => println!("{}", self.accumulator),
+                Opcode::TestLessThan => self.compare(Value::lt)?,
+                Opcode::TestLessEqThan => self.compare(Value::le)?,
+                Opcode::TestGreaterThan => self.compare(Value::gt)?,
+                Opcode::TestGreaterEqThan => self.compare(Value::ge)?,
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::{ByteCode, ByteCodeGenerator};
+    use crate::parser::Parser;
+    use crate::resolver::Resolver;
+    use crate::scanner::Scanner;
+
+    fn compile(source: &str) -> ByteCodeFunction {
+        let tokens = Scanner::new(source.to_string()).scan_tokens();
+        let statements = Parser::new(&tokens).parse().unwrap();
+        Resolver::default().resolve(&statements).unwrap();
+        let mut generator = ByteCodeGenerator::default();
+        generator.intrepret(&statements).unwrap();
+        generator.script()
+    }
+
+    fn run_source(source: &str) -> Vm {
+        let mut vm = Vm::new(compile(source));
+        vm.interpret().unwrap();
+        vm
+    }
+
+    #[test]
+    fn test_function_call() {
+        let vm = run_source(
+            "fun add(a, b) { return a + b; }
+             fun twice(x) { var y = add(x, x); return y; }
+             var result = twice(add(1, 2));",
+        );
+        assert_eq!(vm.variables.get("result"), Some(&Value::Number(6.0)));
+    }
+
+    #[test]
+    fn test_calls_may_reassign_variables() {
+        let vm = run_source(
+            "var a = \"x\";
+             fun assign() { a = 1; }
+             assign();
+             var global = -a;
+             fun f() {
+               var x = \"s\";
+               fun g() { x = 1; }
+               g();
+               return -x;
+             }
+             var captured = f();",
+        );
+        assert_eq!(vm.variables.get("global"), Some(&Value::Number(-1.0)));
+        assert_eq!(vm.variables.get("captured"), Some(&Value::Number(-1.0)));
+    }
+
+    #[test]
+    fn test_uninitialized_variables_are_nil() {
+        let vm = run_source("var r; var global = r; fun f() { var x; return x; } var local = f();");
+        assert_eq!(vm.variables.get("global"), Some(&Value::Nil));
+        assert_eq!(vm.variables.get("local"), Some(&Value::Nil));
+    }
+
+    #[test]
+    fn test_function_without_return_yields_nil() {
+        let vm = run_source("fun noop() {} var result = noop();");
+        assert_eq!(vm.variables.get("result"), Some(&Value::Nil));
+    }
+
+    #[test]
+    fn test_closure_outlives_enclosing_call() {
+        let vm = run_source(
+            "fun makeCounter() {
+               var count = 0;
+               fun increment() { count = count + 1; return count; }
+               return increment;
+             }
+             var first = makeCounter();
+             var second = makeCounter();
+             first();
+             first();
+             var a = first();
+             var b = second();",
+        );
+        assert_eq!(vm.variables.get("a"), Some(&Value::Number(3.0)));
+        assert_eq!(vm.variables.get("b"), Some(&Value::Number(1.0)));
+    }
+
+    #[test]
+    fn test_closures_share_captured_variable() {
+        let vm = run_source(
+            "fun outer() {
+               var x = 1;
+               fun middle() {
+                 fun inner() { x = x + 10; return x; }
+                 return inner;
+               }
+               fun set(value) { x = value; }
+               var f = middle();
+               set(5);
+               f();
+               var y = x;
+               return y;
+             }
+             var result = outer();",
+        );
+        assert_eq!(vm.variables.get("result"), Some(&Value::Number(15.0)));
+    }
+
+    #[test]
+    fn test_block_exit_closes_upvalue() {
+        let vm = run_source(
+            "fun capture() {
+               var f;
+               { var j = 5; fun get() { return j; } f = get; j = 7; }
+               return f;
+             }
+             var get = capture();
+             var result = get();",
+        );
+        assert_eq!(vm.variables.get("result"), Some(&Value::Number(7.0)));
+    }
+
+    #[test]
+    fn test_classes_methods_and_super() {
+        let vm = run_source(
+            "class Animal {
+               init(legs) { this.legs = legs; }
+               describe() { return this.legs; }
+             }
+             class Bird < Animal {
+               init() { super.init(2); this.wings = 2; }
+               describe() {
+                 fun total() { return super.describe() + this.wings; }
+                 return total;
+               }
+             }
+             var bird = Bird();
+             var legs = bird.legs;
+             var total = bird.describe();
+             var limbs = total();
+             bird.legs = 1;
+             var after = bird.init().describe()();",
+        );
+        assert_eq!(vm.variables.get("legs"), Some(&Value::Number(2.0)));
+        assert_eq!(vm.variables.get("limbs"), Some(&Value::Number(4.0)));
+        assert_eq!(vm.variables.get("after"), Some(&Value::Number(4.0)));
+    }
+
+    #[test]
+    fn test_if_else_branches_at_runtime() {
+        let vm = run_source(
+            "fun pick(flag) {
+               var out;
+               if (flag) { out = 1; } else { out = 2; }
+               return out;
+             }
+             fun fib(n) {
+               if (n < 2) return n;
+               return fib(n - 1) + fib(n - 2);
+             }
+             var a = pick(true);
+             var b = pick(false);
+             var c = fib(10);",
+        );
+        assert_eq!(vm.variables.get("a"), Some(&Value::Number(1.0)));
+        assert_eq!(vm.variables.get("b"), Some(&Value::Number(2.0)));
+        assert_eq!(vm.variables.get("c"), Some(&Value::Number(55.0)));
+    }
+
+    #[test]
+    fn test_loops_test_condition_before_body() {
+        let vm = run_source(
+            "var skipped = 0;
+             for (var i = 10; i < 3; i = i + 1) { skipped = skipped + 1; }
+             var sum = 0;
+             var i = 0;
+             while (i < 5) { i = i + 1; sum = sum + i; }
+             fun count(n) {
+               var total = 0;
+               for (var j = 0; j < n; j = j + 1) total = total + 2;
+               return total;
+             }
+             var none = count(0);
+             var some = count(4);",
+        );
+        assert_eq!(vm.variables.get("skipped"), Some(&Value::Number(0.0)));
+        assert_eq!(vm.variables.get("sum"), Some(&Value::Number(15.0)));
+        assert_eq!(vm.variables.get("none"), Some(&Value::Number(0.0)));
+        assert_eq!(vm.variables.get("some"), Some(&Value::Number(8.0)));
+    }
+
+    #[test]
+    fn test_nested_jumps_land_on_their_labels() {
+        let vm = run_source(
+            "fun count(limit) {
+               var i = 0;
+               var evens = 0;
+               while (i < limit) {
+                 var even = false;
+                 var j = 0;
+                 while (j < i) { even = !even; j = j + 1; }
+                 if (even) { evens = evens + 1; } else { evens = evens + 0; }
+                 i = i + 1;
+               }
+               return evens;
+             }
+             var none = count(0);
+             var some = count(5);
+             var after = 0;
+             if (some > 1) { if (none < 1) after = 1; } else after = 2;",
+        );
+        assert_eq!(vm.variables.get("none"), Some(&Value::Number(0.0)));
+        assert_eq!(vm.variables.get("some"), Some(&Value::Number(2.0)));
+        assert_eq!(vm.variables.get("after"), Some(&Value::Number(1.0)));
+    }
+
+    #[test]
+    fn test_logical_operators_short_circuit() {
+        let vm = run_source(
+            "var calls = 0;
+             fun touch(v) { calls = calls + 1; return v; }
+             fun either(a, b) { return a or touch(b); }
+             fun both(a, b) { return a and touch(b); }
+             var first = either(true, 2);
+             var second = either(nil, 2);
+             var third = both(false, 3);
+             var fourth = both(true, 3);
+             var numbers = both(1, 2);
+             var zero = either(0, 5);
+             var branch = 0;
+             if (1) branch = 1; else branch = 2;",
+        );
+        assert_eq!(vm.variables.get("first"), Some(&Value::Boolean(true)));
+        assert_eq!(vm.variables.get("second"), Some(&Value::Number(2.0)));
+        assert_eq!(vm.variables.get("third"), Some(&Value::Boolean(false)));
+        assert_eq!(vm.variables.get("fourth"), Some(&Value::Number(3.0)));
+        assert_eq!(vm.variables.get("numbers"), Some(&Value::Number(2.0)));
+        assert_eq!(vm.variables.get("zero"), Some(&Value::Number(0.0)));
+        assert_eq!(vm.variables.get("branch"), Some(&Value::Number(1.0)));
+        assert_eq!(vm.variables.get("calls"), Some(&Value::Number(3.0)));
+    }
+
+    #[test]
+    fn test_comparisons_produce_booleans() {
+        let vm = run_source(
+            "fun less(x, y) { var b = x < y; return b; }
+             fun atLeast(x, y) { return x >= y; }
+             var yes = less(1, 2);
+             var no = less(2, 1);
+             var edge = atLeast(2, 2);
+             var both = less(1, 2) and atLeast(1, 2);
+             var branch = 0;
+             if (yes) branch = 1;
+             if (less(5, 3) == false) branch = branch + 1;",
+        );
+        assert_eq!(vm.variables.get("yes"), Some(&Value::Boolean(true)));
+        assert_eq!(vm.variables.get("no"), Some(&Value::Boolean(false)));
+        assert_eq!(vm.variables.get("edge"), Some(&Value::Boolean(true)));
+        assert_eq!(vm.variables.get("both"), Some(&Value::Boolean(false)));
+        assert_eq!(vm.variables.get("branch"), Some(&Value::Number(2.0)));
+    }
+
+    #[test]
+    fn test_equality_and_unary_operators() {
+        let vm = run_source(
+            "fun same(a, b) { return a == b; }
+             fun differ(a, b) { return a != b; }
+             fun negate(n) { return -n; }
+             fun not(v) { return !v; }
+             var equal = same(2, 2);
+             var nils = same(nil, nil);
+             var mixed = same(1, true);
+             var unequal = differ(1, 2);
+             var negative = negate(3);
+             var falsy = not(nil);
+             var truthy = not(true);
+             var branch = 0;
+             if (same(1, 1) == true) branch = 1;",
+        );
+        assert_eq!(vm.variables.get("equal"), Some(&Value::Boolean(true)));
+        assert_eq!(vm.variables.get("nils"), Some(&Value::Boolean(true)));
+        assert_eq!(vm.variables.get("mixed"), Some(&Value::Boolean(false)));
+        assert_eq!(vm.variables.get("unequal"), Some(&Value::Boolean(true)));
+        assert_eq!(vm.variables.get("negative"), Some(&Value::Number(-3.0)));
+        assert_eq!(vm.variables.get("falsy"), Some(&Value::Boolean(true)));
+        assert_eq!(vm.variables.get("truthy"), Some(&Value::Boolean(false)));
+        assert_eq!(vm.variables.get("branch"), Some(&Value::Number(1.0)));
+    }
+
+    #[test]
+    fn test_print_compiles_any_expression() {
+        let tokens = Scanner::new(
+            "var a = 1; fun f(x) { print -x; } print a; print (a); print a + 2; print nil; f(a);"
+                .to_string(),
+        )
+        .scan_tokens();
+        let statements = Parser::new(&tokens).parse().unwrap();
+        Resolver::default().resolve(&statements).unwrap();
+        let mut generator = ByteCodeGenerator::default();
+        generator.intrepret(&statements).unwrap();
+        let prints = generator
+            .bytecodes
+            .iter()
+            .filter(|bytecode| matches!(bytecode, ByteCode::Print))
+            .count();
+        assert_eq!(prints, 4);
+    }
+
+    #[test]
+    fn test_strings_concatenate_and_compare() {
+        let vm = run_source(
+            "var a = \"hello\";
+             var b = \" world\";
+             var greeting = a + b;
+             fun join(x, y) { return x + y; }
+             var joined = join(\"lox\", \"vm\");
+             var same = joined == \"loxvm\";
+             var differ = joined != \"lox\";",
+        );
+        assert_eq!(
+            vm.variables.get("greeting"),
+            Some(&Value::String("hello world".to_string()))
+        );
+        assert_eq!(
+            vm.variables.get("joined"),
+            Some(&Value::String("loxvm".to_string()))
+        );
+        assert_eq!(vm.variables.get("same"), Some(&Value::Boolean(true)));
+        assert_eq!(vm.variables.get("differ"), Some(&Value::Boolean(true)));
+    }
+
+    #[test]
+    fn test_operands_see_locals_before_later_operands_assign_them() {
+        let vm = run_source(
+            "fun g(x) { return \"g\"; }
+             fun h(x) { return \"h\"; }
+             fun sum() { var a = 1; return a + (a = 5); }
+             fun call() { var k = g; return k(k = h); }
+             fun args(f) { var a = 1; return f(a, a = 2); }
+             fun first(x, y) { return x; }
+             fun closure() {
+               var a = 1;
+               fun set() { a = 10; return 0; }
+               return a + set();
+             }
+             var sum = sum();
+             var call = call();
+             var args = args(first);
+             var closure = closure();",
+        );
+        assert_eq!(vm.variables.get("sum"), Some(&Value::Number(6.0)));
+        assert_eq!(
+            vm.variables.get("call"),
+            Some(&Value::String("g".to_string()))
+        );
+        assert_eq!(vm.variables.get("args"), Some(&Value::Number(1.0)));
+        assert_eq!(vm.variables.get("closure"), Some(&Value::Number(1.0)));
+    }
+
+    #[test]
+    fn test_nested_expressions_reuse_temporaries() {
+        let vm = run_source(
+            "fun mix(a, b, c, d) { return (a + b) * (c - d) - -(a * (b + c)); }
+             var nested = mix(1, 2, 5, 3);
+             var a = 1;
+             var b = a;
+             a = 5;
+             var sum = a + b;",
+        );
+        assert_eq!(vm.variables.get("nested"), Some(&Value::Number(13.0)));
+        assert_eq!(vm.variables.get("b"), Some(&Value::Number(1.0)));
+        assert_eq!(vm.variables.get("sum"), Some(&Value::Number(6.0)));
+    }
+
+    #[test]
+    fn test_frame_size_is_bounded_by_expression_depth() {
+        let source = "var total = 0; total = total + 1 * 2;\n".repeat(50)
+            + "fun add(a, b) { var c = a + b; return c; }";
+        let tokens = Scanner::new(source).scan_tokens();
+        let statements = Parser::new(&tokens).parse().unwrap();
+        Resolver::default().resolve(&statements).unwrap();
+        let mut generator = ByteCodeGenerator::default();
+        generator.intrepret(&statements).unwrap();
+        let script = generator.script();
+        assert_eq!(script.register_count, 3);
+
+        let [add] = script.functions.as_slice() else {
+            panic!("expected the function `add`");
+        };
+        // R0, the two parameters and `c`. `a + b` needs no temporary since `a`
+        // is read from its own register.
+        assert_eq!(add.register_count, 4);
+    }
+
+    #[test]
+    fn test_accumulator_is_reloaded_where_jumps_land() {
+        let vm = run_source(
+            "fun keep(flag) {
+               var out = 1;
+               if (flag) out = 2;
+               return out;
+             }
+             fun copy() {
+               var a = 3;
+               var b = a;
+               a = 4;
+               return b;
+             }
+             var kept = keep(false);
+             var changed = keep(true);
+             var copied = copy();",
+        );
+        assert_eq!(vm.variables.get("kept"), Some(&Value::Number(1.0)));
+        assert_eq!(vm.variables.get("changed"), Some(&Value::Number(2.0)));
+        assert_eq!(vm.variables.get("copied"), Some(&Value::Number(3.0)));
+    }
+
+    #[test]
+    fn test_block_locals_shadow_globals() {
+        let vm = run_source(
+            "var a = \"global\";
+             var seen;
+             { var a = \"outer\"; { var a = \"inner\"; seen = a; } a = a + \"!\"; }
+             var after = a;
+             var getter;
+             { var hidden = 7; fun get() { return hidden; } getter = get; }
+             var captured = getter();",
+        );
+        assert_eq!(
+            vm.variables.get("seen"),
+            Some(&Value::String("inner".to_string()))
+        );
+        assert_eq!(
+            vm.variables.get("after"),
+            Some(&Value::String("global".to_string()))
+        );
+        assert_eq!(vm.variables.get("hidden"), None);
+        assert_eq!(vm.variables.get("captured"), Some(&Value::Number(7.0)));
+    }
+
+    #[test]
+    fn test_assigning_an_undeclared_global_fails() {
+        let mut vm = Vm::new(compile("x = 1;"));
+        assert_eq!(
+            vm.interpret().unwrap_err().to_string(),
+            "Error occured at line 1: Undefined variable 'x'."
+        );
+        assert_eq!(vm.variables.get("x"), None);
+
+        let mut vm = Vm::new(compile("{ var hidden = 1; }\nhidden = 2;"));
+        let error = vm.interpret().unwrap_err();
+        assert!(matches!(error, LoxErrors::RunTimeException(_)));
+        assert_eq!(
+            error.to_string(),
+            "Error occured at line 2: Undefined variable 'hidden'."
+        );
+        assert_eq!(vm.variables.get("hidden"), None);
+    }
+
+    #[test]
+    fn test_type_errors_are_only_raised_when_the_code_runs() {
+        let vm = run_source(
+            "fun never() { return -\"a\"; }
+             var result = 2;
+             if (false) { result = 1 - \"x\"; }",
+        );
+        assert_eq!(vm.variables.get("result"), Some(&Value::Number(2.0)));
+
+        let mut vm = Vm::new(compile("var a = 1;\nvar b = -\"a\";"));
+        assert_eq!(
+            vm.interpret().unwrap_err().to_string(),
+            "Error occured at line 2: Operand must be a number."
+        );
+    }
+
+    #[test]
+    fn test_literals_record_their_own_line() {
+        // LdaNil, DefineGlobal i, then LdaConstant 0 at offset 3 for the
+        // literal and StaGlobal i at offset 5 for the assignment.
+        let script = compile("var i;\ni =\n  0;");
+        assert_eq!(script.lines.runs(), &[(0, 1), (3, 3), (5, 2)]);
+    }
+
+    #[test]
+    fn test_unbounded_recursion_overflows_the_stack() {
+        let mut vm = Vm::new(compile("fun f() {\n  f();\n}\nf();"));
+        let error = vm.interpret().unwrap_err();
+        assert!(matches!(error, LoxErrors::RunTimeException(_)));
+        assert_eq!(
+            error.to_string(),
+            "Error occured at line 2: Stack overflow."
+        );
+
+        let vm = run_source(
+            "fun count(n) { if (n == 0) return 0; return 1 + count(n - 1); }
+             var depth = count(60);",
+        );
+        assert_eq!(vm.variables.get("depth"), Some(&Value::Number(60.0)));
+    }
+
+    #[test]
+    fn test_runtime_errors_report_their_line() {
+        let mut vm = Vm::new(compile(
+            "fun add(a, b) {
+               return a + b;
+             }
+             print add(1, true);",
+        ));
+        let error = vm.interpret().unwrap_err();
+        assert!(matches!(error, LoxErrors::RunTimeException(_)));
+        assert_eq!(
+            error.to_string(),
+            "Error occured at line 2: Operands must be two numbers or two strings."
+        );
+
+        let mut vm = Vm::new(compile("var a = 1;\nvar b = a();"));
+        assert_eq!(
+            vm.interpret().unwrap_err().to_string(),
+            "Error occured at line 2: Can only call functions and classes."
+        );
+    }
+}